@@ -14,13 +14,16 @@
 
 //! cargo run --bin account-write
 
-use std::{env, path::PathBuf};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use dotenv::dotenv;
 
-use iota_sdk::Wallet;
-use iota_sdk::client::{request_funds_from_faucet, Client};
-use purity::account::PurityAccountExt;
+use iota_sdk::client::{constants::SHIMMER_COIN_TYPE, secret::SecretManager, Client};
+use iota_sdk::crypto::keys::bip44::Bip44;
+use purity::{PurityConfig, WriteOptions};
+use purity::account::{BatchEntry, PurityAccountExt, SweepOptions};
+use purity::client::{read_data, read_data_decrypted};
+use purity::encryption::{DecryptionKey, StrongholdKey};
+use purity::policy::UnlockPolicy;
 use purity::utils::{print_addresses_with_funds, create_or_recover_wallet, print_accounts, print_addresses, sync_print_balance, request_faucet_funds};
 
 extern crate pretty_env_logger;
@@ -36,10 +39,12 @@ async fn main() -> anyhow::Result<()> {
     // This example uses dotenv, which is not safe for use in production
     dotenv().ok();
 
+    let config = PurityConfig::from_env()?;
+
     // Create a client
-    let client = Client::builder().with_node(&env::var("NODE_URL").unwrap())?.finish().await?;
+    let client = Client::builder().with_node(&config.node_url)?.finish().await?;
     // Create the wallet
-    let wallet = create_or_recover_wallet().await?;
+    let wallet = create_or_recover_wallet(&config).await?;
     // wallet.start_background_syncing(None, None).await?;
     
     // Create a new account
@@ -61,7 +66,7 @@ async fn main() -> anyhow::Result<()> {
     let address = &account.generate_ed25519_addresses(1, None).await?[0];
     println!("Generated address: {}", address.address());
 
-    request_faucet_funds(&client, address.address(), &env::var("FAUCET_URL").unwrap()).await?;
+    request_faucet_funds(&client, address.address(), config.faucet_url()?).await?;
     
     let tag = "wallet-lib";
     for i in 0..2 {   
@@ -74,12 +79,14 @@ async fn main() -> anyhow::Result<()> {
         start = Instant::now();
         
        
-        let _tid = account.write_data(//write_with_wallet(
+        let _receipt = account.write_data(//write_with_wallet(
+            &config,
             // &account, 
             address.address(), 
             tag, 
             data, //  metadata.as_str().as_bytes().to_vec(),
-            None
+            UnlockPolicy::timelock_in(Duration::from_secs(60*60))?.into(),
+            None,
         ).await;
         duration = start.elapsed().as_millis();
         println!("{},{:?}",i, duration );
@@ -87,6 +94,35 @@ async fn main() -> anyhow::Result<()> {
         // account.write();
     }
     
+    // Many small records packed in few transactions
+    let entries = (0..10)
+        .map(|i| BatchEntry::new(tag, format!("reading {i}").into_bytes(), UnlockPolicy::None))
+        .collect();
+    let receipts = account.write_data_batch(&config, address.address(), entries).await?;
+    println!("Batch written: {:?}", receipts.iter().map(|r| r.output_id()).collect::<Vec<_>>());
+
+    // Payloads larger than a single output are fragmented and rebuilt by read_data
+    let document = (0..20_000).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    let receipt = account.write_data(&config, address.address(), tag, document.clone(), WriteOptions::default(), None).await?;
+    println!("Fragmented in {} outputs, deposit: {}", receipt.output_ids.len(), receipt.storage_deposit);
+    let read_back = read_data(&client, receipt.output_id()).await?;
+    println!("Fragmented document read back: {}", read_back == document);
+
+    // Encrypted for the key kept in the wallet Stronghold, only its owner can read it back
+    if let SecretManager::Stronghold(stronghold) = &*wallet.get_secret_manager().read().await {
+        let key = StrongholdKey::load_or_generate(stronghold).await?;
+        let secret = b"sensor reading: 36.6".to_vec();
+        // Signed by the first key of the wallet, readers check it against the publisher key
+        let chain = Bip44::new(SHIMMER_COIN_TYPE);
+        let options = WriteOptions::default().with_recipient(key.public_key()).with_signer(chain);
+        let receipt = account.write_data(&config, address.address(), tag, secret.clone(), options, None).await?;
+        println!("Encrypted data read back: {}", read_data_decrypted(&client, receipt.output_id(), &key).await? == secret);
+    }
+
+    // Report the deposits that could be reclaimed from data outputs no longer locked
+    let report = account.sweep_data_outputs(&SweepOptions { dry_run: true, ..Default::default() }, None).await?;
+    println!("Sweepable data outputs: {}, reclaimable: {}", report.swept.len(), report.reclaimed);
+
     // Consolidate unspent outputs and print the consolidation transaction IDs
     // Set `force` to true to force the consolidation even though the `output_consolidation_threshold` isn't reached
     // let transaction = account.consolidate_outputs(true, None).await?;
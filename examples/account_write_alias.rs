@@ -14,11 +14,10 @@
 
 //! cargo run --bin test-alias
 
-use std::{env, path::PathBuf};
 use dotenv::dotenv;
 
-use iota_sdk::Wallet;
-use iota_sdk::client::{request_funds_from_faucet, Client};
+use iota_sdk::client::Client;
+use purity::{Confirmation, PurityConfig};
 use purity::account::PurityAccountExt;
 use purity::utils::{create_or_recover_wallet, print_accounts, print_addresses, sync_print_balance, print_addresses_with_funds, request_faucet_funds};
 
@@ -32,10 +31,12 @@ async fn main() -> anyhow::Result<()> {
     // This example uses dotenv, which is not safe for use in production
     dotenv().ok();
 
+    let config = PurityConfig::from_env()?;
+
     // Create a client
-    let client = Client::builder().with_node(&env::var("NODE_URL").unwrap())?.finish().await?;
+    let client = Client::builder().with_node(&config.node_url)?.finish().await?;
     // Create the wallet
-    let wallet = create_or_recover_wallet().await?;
+    let wallet = create_or_recover_wallet(&config).await?;
     // wallet.start_background_syncing(None, None).await?;
     
     // Create a new account
@@ -57,11 +58,17 @@ async fn main() -> anyhow::Result<()> {
     let address = &account.generate_ed25519_addresses(1, None).await?[0];
     println!("Generated address: {}", address.address());
 
-    request_faucet_funds(&client, address.address(), &env::var("FAUCET_URL").unwrap()).await?;
+    request_faucet_funds(&client, address.address(), config.faucet_url()?).await?;
     
     let random_tag = (0..10).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
     let random_metadata = (0..10).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
-    let _ = account.write_alias_data(address.address(), random_tag, random_metadata, None).await;
+    let receipt = account.write_alias_data(&config, address.address(), random_tag.clone(), random_metadata, None, Confirmation::default()).await?;
+    let alias_id = receipt.alias_id.expect("alias writes name their alias");
+    println!("Alias created: {alias_id}");
+
+    let random_metadata = (0..10).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    account.write_alias_data(&config, address.address(), random_tag, random_metadata, Some(alias_id), Confirmation::default()).await?;
+    println!("Alias updated: {alias_id}");
     println!("end");
 
     Ok(())
@@ -12,9 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 use std::thread::sleep;
-use purity::client::read;
+use purity::PurityConfig;
+use purity::policy::UnlockPolicy;
+use purity::storage::PurityStorage;
+use purity::client::read_records;
 use purity::client::write_with_client;
 use purity::client::setup_with_client;
 
@@ -24,28 +27,30 @@ async fn main() -> anyhow::Result<()> {
 
     dotenv::dotenv().ok();
     
-    // let mut storage: Vec<BlockId> = Vec::new();
-
     let tag = "licat-10";
     let metadata = "this is metadata";
 
-    let ( mut secret_manager, client, address ) = setup_with_client().await?;
+    let config = PurityConfig::from_env()?;
+    let storage = PurityStorage::open(config.storage_path()?)?;
+    let ( mut secret_manager, client, address ) = setup_with_client(&config).await?;
 
-    let expiration = (SystemTime::now() + Duration::from_secs(120))
-    .duration_since(UNIX_EPOCH)
-    .expect("clock went backwards")
-    .as_secs()
-    .try_into()
-    .unwrap();
+    let expiration = UnlockPolicy::expiration_in(address, Duration::from_secs(120))?;
 
-    write_with_client(&mut secret_manager, &client, address, tag, metadata, Some(expiration)).await?;
+    let receipt = write_with_client(&config, &mut secret_manager, &client, address, tag, metadata, expiration.into(), Some(&storage)).await?;
+    println!("Receipt: {}", serde_json::to_string(&receipt)?);
 
     sleep(Duration::from_millis(7000));
 
-    write_with_client(&mut secret_manager, &client, address, tag, metadata, None).await?;
+    write_with_client(&config, &mut secret_manager, &client, address, tag, metadata, UnlockPolicy::None.into(), Some(&storage)).await?;
 
     sleep(Duration::from_millis(5000));
-    read(&client, tag, address).await?;
+    for record in read_records(&client, tag, Some(address)).await? {
+        println!("Read: {} {:?} spent: {}", record.output_id, String::from_utf8_lossy(&record.metadata), record.is_spent);
+    }
+
+    for record in storage.records_by_tag(tag)? {
+        println!("Stored: {} at {} ({} B)", record.output_id, record.timestamp, record.size);
+    }
     
 
 
@@ -12,33 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
-use purity::client::read;
-
-use iota_sdk::{
-    types::block::{output::OutputId, address::Bech32Address}, 
-    client::Client
-};
+use futures::StreamExt;
+use iota_sdk::{client::Client, types::block::address::Bech32Address};
+use purity::client::{subscribe, Subscription, SubscriptionOptions};
+use purity::PurityConfig;
 
+// Usage: cargo run --example reader [tag] [address]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
 
     dotenv::dotenv().ok();
 
-    let tag = "wallet-lib";
-    let client = Client::builder().with_node(&std::env::var("NODE_URL").unwrap())?.finish().await?;
-    let addr = "rms1qplyhddljvsu7sx68d4gsk3sxq9zj797mvzalq09q2r9tx6yknne6gxqw26";
-    let mut id_set: HashSet<OutputId> = HashSet::new();
-    loop {
-        
-        let outputs =  read(&client, tag, Bech32Address::try_from_str(addr)?).await?;
-        outputs.iter().for_each(|output| {
-            if !id_set.contains(&output) {
-                id_set.insert(output.clone());
-                println!("Output ID: {output:#?}");
-            }
-        });
-
+    let tag = std::env::args().nth(1).unwrap_or_else(|| "wallet-lib".to_string());
+    let subscription = match std::env::args().nth(2) {
+        Some(addr) => Subscription::TagAndAddress(tag, Bech32Address::try_from_str(addr)?),
+        None => Subscription::Tag(tag),
+    };
+    let client = Client::builder().with_node(&PurityConfig::from_env()?.node_url)?.finish().await?;
+
+    let mut records = subscribe(&client, subscription, SubscriptionOptions::default()).await;
+    while let Some(record) = records.next().await {
+        let record = record?;
+        println!("Output ID: {} ({} bytes)", record.output_id, record.metadata.len());
     }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,314 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Writes of many records packed into few transactions.
+//!
+//! Data outputs are grouped so that each transaction stays under the output count limit, one
+//! output is left for the remainder, and under a byte budget that keeps the block below 32 KiB.
+//! Payloads that need fragmentation are written on their own, like `write_data` does. Each
+//! transaction waits for its block as the most demanding `Confirmation` of its records asks. When a
+//! transaction fails, the receipts of the records already written come back in
+//! [`PurityError::PartialBatch`].
+
+use async_trait::async_trait;
+use iota_sdk::{
+    packable::PackableExt,
+    types::block::{
+        address::Bech32Address,
+        output::{Output, OUTPUT_COUNT_MAX},
+    },
+    wallet::account::Account,
+};
+
+use crate::config::PurityConfig;
+use crate::confirmation::Confirmation;
+use crate::ledger::{Ledger, SentBlock};
+use crate::error::{PurityError, Result};
+use crate::fragment;
+use crate::options::WriteOptions;
+
+use crate::timing::{Phase, WriteTimings};
+use crate::writer::{data_output, output_ids_of, WriteReceipt};
+
+use super::purity_account::{send_timed, submitted_block, write_encoded};
+
+/// Data outputs in a transaction, one output is left for the remainder.
+pub const MAX_OUTPUTS_PER_TRANSACTION: usize = OUTPUT_COUNT_MAX as usize - 1;
+/// Serialized size of the data outputs in a transaction, the rest of the block is left to inputs and unlocks.
+pub const MAX_OUTPUT_BYTES_PER_TRANSACTION: usize = 24 * 1024;
+
+/// A record of a batch write.
+#[derive(Clone, Debug)]
+pub struct BatchEntry {
+    pub tag: String,
+    pub payload: Vec<u8>,
+    pub options: WriteOptions,
+}
+
+impl BatchEntry {
+    pub fn new(tag: impl Into<String>, payload: Vec<u8>, options: impl Into<WriteOptions>) -> Self {
+        Self {
+            tag: tag.into(),
+            payload,
+            options: options.into(),
+        }
+    }
+}
+
+/// Sends the transactions of a batch write, from a wallet account or with a bare secret manager.
+#[async_trait]
+pub(crate) trait BatchSender: Sync {
+    fn ledger(&self) -> &dyn Ledger;
+
+    async fn encode(&self, options: &WriteOptions, tag: &str, payload: Vec<u8>) -> Result<Vec<u8>>;
+
+    async fn send(&self, outputs: Vec<Output>) -> Result<SentBlock>;
+
+    /// Makes the remainder of the last transaction available to the next one.
+    async fn refresh(&self, timings: &mut WriteTimings) -> Result<()>;
+
+    /// Writes a record on its own, fragmenting it.
+    async fn write_encoded(
+        &self,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        tag: &str,
+        metadata: Vec<u8>,
+        payload_len: usize,
+        options: WriteOptions,
+    ) -> Result<WriteReceipt>;
+}
+
+#[async_trait]
+impl BatchSender for Account {
+    fn ledger(&self) -> &dyn Ledger {
+        self.client()
+    }
+
+    async fn encode(&self, options: &WriteOptions, tag: &str, payload: Vec<u8>) -> Result<Vec<u8>> {
+        options.encode(&*self.get_secret_manager().read().await, tag.as_bytes(), payload).await
+    }
+
+    async fn send(&self, outputs: Vec<Output>) -> Result<SentBlock> {
+        let mut timings = WriteTimings::default();
+        let t = send_timed(self, outputs, &mut timings).await?;
+        Ok(SentBlock {
+            block_id: submitted_block(&t)?,
+            transaction_id: t.transaction_id,
+            outputs: t.payload.essence().as_regular().outputs().to_vec(),
+            timings,
+        })
+    }
+
+    async fn refresh(&self, timings: &mut WriteTimings) -> Result<()> {
+        timings.measure_async(Phase::Sync, self.sync(None)).await?;
+        Ok(())
+    }
+
+    async fn write_encoded(
+        &self,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        tag: &str,
+        metadata: Vec<u8>,
+        payload_len: usize,
+        options: WriteOptions,
+    ) -> Result<WriteReceipt> {
+        write_encoded(self, config, address, tag, metadata, payload_len, options).await
+    }
+}
+
+pub(crate) async fn write_batch(
+    sender: &impl BatchSender,
+    config: &PurityConfig,
+    address: &Bech32Address,
+    entries: Vec<BatchEntry>,
+) -> Result<Vec<WriteReceipt>> {
+    let rent_structure = sender.ledger().rent_structure().await?;
+    let token_supply = sender.ledger().token_supply().await?;
+    let mut receipts: Vec<Option<WriteReceipt>> = vec![None; entries.len()];
+    // Payload and encoded length of each entry
+    let mut lens = Vec::with_capacity(entries.len());
+
+    // Build every output first, so invalid entries fail the batch before anything is sent
+    let mut outputs = Vec::with_capacity(entries.len());
+    let mut fragmented = Vec::new();
+    let mut confirmations = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.into_iter().enumerate() {
+        confirmations.push(entry.options.confirmation);
+        let payload_len = entry.payload.len();
+        let metadata = sender.encode(&entry.options, &entry.tag, entry.payload).await?;
+        lens.push((payload_len, metadata.len()));
+        if fragment::needs_fragmentation(&metadata) {
+            fragmented.push((index, entry.tag, metadata, payload_len, entry.options));
+            continue;
+        }
+        let output = data_output(address, &entry.tag, metadata, &entry.options, rent_structure, token_supply)?;
+        outputs.push((index, output));
+    }
+
+    let batches = pack(outputs);
+    let transactions = batches.len() + fragmented.len();
+    log::info!("Writing {} records in {} transactions", receipts.len(), transactions);
+
+    let sent = async {
+        for (n, batch) in batches.into_iter().enumerate() {
+            let (indexes, outputs): (Vec<usize>, Vec<Output>) = batch.into_iter().unzip();
+            // The records share the transaction, it waits as the most demanding of them asks
+            let confirmation = indexes
+                .iter()
+                .map(|i| confirmations[*i])
+                .reduce(Confirmation::strongest)
+                .expect("batches are never empty");
+            // Like chunks, a transaction whose remainder funds the next one waits for a milestone
+            let confirmation = if n + 1 < transactions { confirmation.for_chunks() } else { confirmation };
+            let sent = sender.send(outputs.clone()).await?;
+            let mut timings = sent.timings;
+            let (block_id, inclusion) = timings
+                .measure_async(Phase::InclusionWait, sender.ledger().confirm(&sent.block_id, confirmation))
+                .await?;
+            if !inclusion.satisfies(&confirmation) {
+                if n + 1 < transactions {
+                    return Err(PurityError::Unconfirmed(block_id, inclusion));
+                }
+                log::warn!("Block {block_id} is {inclusion}");
+            }
+            sender.refresh(&mut timings).await?;
+
+            let output_ids = output_ids_of(sent.transaction_id, &sent.outputs, &outputs)?;
+            let explorer_url = config.explorer_block_url(block_id);
+            for ((index, output), output_id) in indexes.into_iter().zip(outputs).zip(output_ids) {
+                let (payload_len, encoded_len) = lens[index];
+                receipts[index] = Some(WriteReceipt {
+                    transaction_id: sent.transaction_id,
+                    block_id,
+                    output_ids: vec![output_id],
+                    storage_deposit: output.amount(),
+                    payload_len,
+                    encoded_len,
+                    inclusion,
+                    timings,
+                    explorer_url: explorer_url.clone(),
+                    alias_id: None,
+                });
+            }
+        }
+        for (index, tag, metadata, payload_len, options) in fragmented {
+            receipts[index] = Some(sender.write_encoded(config, address, &tag, metadata, payload_len, options).await?);
+        }
+        Ok(())
+    }
+    .await;
+
+    match sent {
+        Ok(()) => Ok(receipts.into_iter().map(|r| r.expect("every entry is written")).collect()),
+        Err(e) if receipts.iter().all(Option::is_none) => Err(e),
+        Err(e) => Err(PurityError::PartialBatch {
+            written: receipts,
+            source: Box::new(e),
+        }),
+    }
+}
+
+// Groups the data outputs in transactions, in order, under the output count and byte limits
+fn pack(outputs: Vec<(usize, Output)>) -> Vec<Vec<(usize, Output)>> {
+    let mut batches: Vec<Vec<(usize, Output)>> = Vec::new();
+    let mut batch_bytes = 0;
+    for (index, output) in outputs {
+        let len = output.packed_len();
+        let full = batches.last().is_none_or(|batch| {
+            batch.len() == MAX_OUTPUTS_PER_TRANSACTION || batch_bytes + len > MAX_OUTPUT_BYTES_PER_TRANSACTION
+        });
+        if full {
+            batches.push(Vec::new());
+            batch_bytes = 0;
+        }
+        batch_bytes += len;
+        batches.last_mut().expect("a batch was just pushed").push((index, output));
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+    use iota_sdk::types::block::output::RentStructure;
+
+    use super::*;
+    use crate::client::ClientWriter;
+    use crate::ledger::{SimulatedLedger, SHIMMER_TOKEN_SUPPLY};
+    use crate::secret::{create_secret_manager, SecretSource};
+
+    fn address() -> Bech32Address {
+        Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr")
+    }
+
+    fn outputs(count: usize, len: usize) -> Vec<(usize, Output)> {
+        (0..count)
+            .map(|i| {
+                let metadata = vec![i as u8; len];
+                let output = data_output(&address(), "t", metadata, &WriteOptions::default(), RentStructure::default(), SHIMMER_TOKEN_SUPPLY);
+                (i, output.unwrap())
+            })
+            .collect()
+    }
+
+    async fn writer(ledger: SimulatedLedger) -> ClientWriter<SimulatedLedger> {
+        let secret_manager = create_secret_manager(SecretSource::HexSeed(hex::encode([5; 32]))).await.unwrap();
+        let config = PurityConfig::builder().with_node_url("simulated").finish().unwrap();
+        ClientWriter::new(ledger, secret_manager, config)
+    }
+
+    #[test]
+    fn batches_stay_under_the_output_and_byte_limits() {
+        let sizes = |batches: Vec<Vec<(usize, Output)>>| batches.iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(sizes(pack(outputs(200, 10))), [MAX_OUTPUTS_PER_TRANSACTION, 200 - MAX_OUTPUTS_PER_TRANSACTION]);
+        assert_eq!(sizes(pack(outputs(5, 7000))), [3, 2]);
+
+        let batches = pack(outputs(5, 7000));
+        for batch in &batches {
+            assert!(batch.iter().map(|(_, o)| o.packed_len()).sum::<usize>() <= MAX_OUTPUT_BYTES_PER_TRANSACTION);
+        }
+        let order: Vec<usize> = batches.into_iter().flatten().map(|(i, _)| i).collect();
+        assert_eq!(order, [0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn failed_transaction_returns_the_records_already_written() {
+        let writer = writer(SimulatedLedger::new().with_send_limit(1)).await;
+        let entries = (0..4u8).map(|i| BatchEntry::new("t", vec![i; 7000], WriteOptions::default())).collect();
+
+        let Err(PurityError::PartialBatch { written, source }) = writer.write_batch(&address(), entries).await else {
+            panic!("expected a partial batch");
+        };
+        assert!(matches!(*source, PurityError::NodeUnreachable(None)));
+        assert_eq!(written.len(), 4);
+        assert!(written[3].is_none());
+        let receipts: Vec<&WriteReceipt> = written[..3].iter().map(|r| r.as_ref().unwrap()).collect();
+        assert!(receipts.iter().all(|r| r.transaction_id == receipts[0].transaction_id && r.payload_len == 7000));
+
+        let mut booked = writer.ledger().basic_output_ids("t", None).await.unwrap();
+        booked.sort();
+        let mut output_ids: Vec<_> = receipts.iter().flat_map(|r| r.output_ids.clone()).collect();
+        output_ids.sort();
+        assert_eq!(booked, output_ids);
+    }
+
+    #[tokio::test]
+    async fn failure_before_any_write_is_returned_as_is() {
+        let writer = writer(SimulatedLedger::new().with_send_limit(0)).await;
+        let entries = vec![BatchEntry::new("t", b"data".to_vec(), WriteOptions::default())];
+        assert!(matches!(writer.write_batch(&address(), entries).await, Err(PurityError::NodeUnreachable(None))));
+    }
+}
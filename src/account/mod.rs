@@ -14,6 +14,13 @@
 
 // #[cfg(feature = "iota-wallet")]
 pub use purity_account::PurityAccountExt;
+pub use batch::{BatchEntry, MAX_OUTPUTS_PER_TRANSACTION, MAX_OUTPUT_BYTES_PER_TRANSACTION};
+pub use sweep::{SweepOptions, SweepReport, SweptOutput};
+pub use writer::AccountWriter;
+pub(crate) use batch::{write_batch, BatchSender};
 
 // #[cfg(feature = "iota-wallet")]
-mod purity_account;
\ No newline at end of file
+mod batch;
+mod purity_account;
+mod sweep;
+mod writer;
@@ -12,39 +12,89 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH, Instant};
-use anyhow::Ok;
+use std::time::Instant;
 use async_trait::async_trait;
 
-use iota_sdk::{wallet::account::Account, types::block::{address::Bech32Address, output::feature::SenderFeature}};
+use iota_sdk::{wallet::account::{types::Transaction, Account, CreateAliasParams}, types::block::{address::Bech32Address, BlockId}};
+use crate::config::PurityConfig;
+use crate::confirmation::Confirmation;
+use crate::error::{PurityError, Result};
+use crate::estimate::{self, WriteEstimate, WritePath};
+use crate::fragment;
+use crate::ledger::Ledger;
+use crate::options::WriteOptions;
+use crate::storage::{record_write, PurityStorage};
+use crate::timing::{Phase, WriteTimings};
+use crate::writer::{chunk_output, data_output, output_ids_of, WriteReceipt, WriteRequest};
+
+use super::batch::{self, BatchEntry};
+use super::sweep::{self, SweepOptions, SweepReport};
+
 use iota_sdk::types::block::output::{
-    feature::{TagFeature, MetadataFeature},
-    unlock_condition::{ 
-        AddressUnlockCondition,
-        UnlockCondition,
-        TimelockUnlockCondition
-    },
-    BasicOutputBuilder, Feature, OutputId, AliasId,
+    AliasOutputBuilder, Output, OutputId, AliasId,
 };
 
 #[async_trait]
 pub trait PurityAccountExt {
     fn hello(&self);
+    /// Writes `metadata` under `tag`, fragmenting it if it doesn't fit a single output.
+    ///
+    /// The receipt holds the state of the block when the `Confirmation` of `options` stopped
+    /// waiting, see `WriteReceipt::confirmed` to turn a shortfall into an error. With `storage`
+    /// a confirmed write is also recorded in the local store.
     async fn write_data(
         &self,
+        config: &PurityConfig,
         address: &Bech32Address,
         tag: &str, 
         metadata: Vec<u8>,
-        expiration: Option<u32>
-    ) -> anyhow::Result<OutputId>;
+        options: WriteOptions,
+        storage: Option<&PurityStorage>,
+    ) -> Result<WriteReceipt>;
 
+    /// Writes many records, packed in as few transactions as the protocol limits allow.
+    ///
+    /// Returns the receipt of each entry, in the order of `entries`; entries sharing a transaction
+    /// share its ids, state and timings. A failure after some entries were written is a
+    /// `PurityError::PartialBatch` holding their receipts.
+    async fn write_data_batch(
+        &self,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        entries: Vec<BatchEntry>,
+    ) -> Result<Vec<WriteReceipt>>;
+
+    /// Writes `metadata` as the state metadata of the alias `alias_id`, or of a new alias
+    /// whose immutable metadata is `tag`, waiting for its block as `confirmation` asks.
+    ///
+    /// The receipt names the alias in `WriteReceipt::alias_id`.
     async fn write_alias_data(
         &self,
+        config: &PurityConfig,
         address: &Bech32Address,
         tag: Vec<u8>, 
         metadata: Vec<u8>,
         alias_id: Option<AliasId>,
-    ) -> anyhow::Result<String>;
+        confirmation: Confirmation,
+    ) -> Result<WriteReceipt>;
+
+    /// Computes the deposit `write_data` would lock, and whether the account balance covers it, without sending anything.
+    async fn estimate_write_data(
+        &self,
+        address: &Bech32Address,
+        tag: &str,
+        metadata: &[u8],
+        options: &WriteOptions,
+    ) -> Result<WriteEstimate>;
+
+    /// Consumes the data outputs whose timelock or expiration passed, returning their storage deposit to the account.
+    ///
+    /// With `archive` the content of each output is saved to the local store once the sweep is included.
+    async fn sweep_data_outputs(
+        &self,
+        options: &SweepOptions,
+        archive: Option<&PurityStorage>,
+    ) -> Result<SweepReport>;
 }
 
 #[async_trait]
@@ -55,116 +105,282 @@ impl PurityAccountExt for Account {
 
     async fn write_data(
         &self,
+        config: &PurityConfig,
         address: &Bech32Address,
         tag: &str, 
         metadata: Vec<u8>,
-        _expiration: Option<u32>
-    ) -> anyhow::Result<OutputId> {
-        log::info!("Start write_data");
-        let write_data_start_time = Instant::now();
-        let len_metadata = metadata.len();
-        let timelock = (SystemTime::now() + Duration::from_secs(60*60))
-            .duration_since(UNIX_EPOCH)
-            .expect("clock went backwards")
-            .as_secs()
-            .try_into()
-            .unwrap();
-        // Send native tokens together with the required storage deposit
-        let rent_structure = self.client().get_rent_structure().await?;
-    
-        let output = BasicOutputBuilder::new_with_minimum_storage_deposit(rent_structure)
-            .add_feature(Feature::Tag(TagFeature::new(tag.as_bytes().to_vec())?))
-            .add_feature(Feature::Metadata(MetadataFeature::new(metadata)?))
-            // .add_feature(Feature::Sender(SenderFeature::new(address)))
-            .add_unlock_condition(UnlockCondition::Address(AddressUnlockCondition::new(address)))
-            .add_unlock_condition(UnlockCondition::Timelock(TimelockUnlockCondition::new(timelock)?))
-            .finish_output(self.client().get_token_supply().await?)?;
-
-        let outputs = vec![
-            output
-        ];
-    
-        // Send back with custom provided input
-        // let custom_input = &account.unspent_outputs(None).await?;
-        
-        // println!("{:?}", custom_input );
-    
-        // let unspent_outputs = account.unspent_outputs(None).await?;
-        // println!("Unspent outputs: {unspent_outputs:#?}");
-    
-        // println!("Output Ids: [");
-        // for o in unspent_outputs  {
-        //     println!("{}", o.output_id)
-        // }   
-        // println!("]");
-    
-    
-        let options = None; 
-        // if custom_input.len() != 0 {
-        //     options = Some(TransactionOptions {
-        //         custom_inputs: Some(vec![custom_input[0].output_id]),
-        //         ..Default::default()
-        //     });
-        // }
-    
-    
-    
-        //let transaction = account.send(outputs, options).await?;
-        let return_value = match self.send_outputs(outputs, options).await {
-            anyhow::Result::Ok(t) => {
-                // Save the transaction in a variable
-                           
-                let _ = self
-                    .retry_transaction_until_included(&t.transaction_id, None, None)
-                    .await;
-                println!("Block on Explorer: {}/block/{}", std::env::var("EXPLORER_URL").unwrap(), t.block_id.expect("no block created yet"));
-                Ok(OutputId::new(t.transaction_id, 0 as u16)?)  // TODO: fragmentation will require something else
-            } 
-            Err(err) => {
-                // Print the error message and throw an exception
-                log::warn!("Error sending transaction: {}", err);
-                anyhow::bail!(err)
-                //panic!("Transaction send failed");
-            }
-        };
-           
-        log::info!("Finished write_data in {:.2?}", write_data_start_time.elapsed());
-        println!("Finished write_data in {:.2?} - metadata len: {} B", write_data_start_time.elapsed(), len_metadata);
-        let _ = self.sync(None).await?;
-        return_value
+        options: WriteOptions,
+        storage: Option<&PurityStorage>,
+    ) -> Result<WriteReceipt> {
+        let payload = storage.is_some().then(|| metadata.clone());
+        let receipt = write_request(self, config, WriteRequest::new(*address, tag, metadata, options)).await?;
+        record_write(storage, tag, &receipt, payload.as_deref().unwrap_or_default())?;
+        Ok(receipt)
     }
 
     async fn write_alias_data(
         &self,
-        _address:  &Bech32Address,
-        _tag: Vec<u8>, 
-        _metadata: Vec<u8>,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        tag: Vec<u8>, 
+        metadata: Vec<u8>,
         alias_id: Option<AliasId>,
-    ) -> anyhow::Result<String> {
-
-        // TODO: alias_options
-        // let alias_options = AliasOutputOptions {
-        //     address: None,
-        //     immutable_metadata: Some(b"some immutable alias metadata".to_vec()),
-        //     metadata: Some(b"some alias metadata".to_vec()),
-        //     state_metadata: Some(b"some alias state metadata".to_vec()),
-        // };
-        
-        
-        if let Some(_a_id) = alias_id {
-            // let alias_output = self.get_alias_output(alias_id).await?; // TODO: retrieve output
-            // TODO: update output
-            // self.send() // TODO: publish updated output
+        confirmation: Confirmation,
+    ) -> Result<WriteReceipt> {
+        log::info!("Start write_alias_data");
+        let write_alias_data_start_time = Instant::now();
+        let payload_len = metadata.len();
+
+        let (t, alias_id, output_id) = if let Some(alias_id) = alias_id {
+            // Retrieve the current state of the alias owned by this account
+            let output_data = self.unspent_alias_output(&alias_id).await?
+                .ok_or_else(|| PurityError::NotFound(format!("alias {alias_id}")))?;
+            let alias_output = match &output_data.output {
+                Output::Alias(alias_output) => alias_output,
+                _ => return Err(PurityError::InvalidData(format!("output {} is not an alias output", output_data.output_id))),
+            };
+
+            // The tag is stored as immutable metadata, so it can't change across state transitions
+            let immutable_tag = alias_output.immutable_features().metadata().map(|m| m.data());
+            if immutable_tag != Some(tag.as_slice()) {
+                return Err(PurityError::InvalidInput(format!("tag does not match the immutable metadata of alias {alias_id}")));
+            }
+
+            // Move the alias to the next state carrying the new metadata
+            let rent_structure = self.client().get_rent_structure().await?;
+            let output = AliasOutputBuilder::from(alias_output)
+                .with_alias_id(alias_id)
+                .with_state_index(alias_output.state_index() + 1)
+                .with_state_metadata(metadata)
+                .with_minimum_storage_deposit(rent_structure)
+                .finish_output(self.client().get_token_supply().await?)?;
+
+            let t = self.send_outputs(vec![output], None).await?;
+            let output_id = alias_output_id(&t, &alias_id)?;
+            (t, alias_id, output_id)
         } else {
-            // TODO: create alias output for the first time 
-            let transaction = self.create_alias_output(None, None).await?;
-            println!(
-                "Block sent: {}",
-                transaction.block_id.expect("no block created yet")
-            );
+            // Create the alias output for the first time, the tag is its immutable metadata
+            let params = CreateAliasParams {
+                address: Some(*address),
+                immutable_metadata: Some(tag),
+                metadata: None,
+                state_metadata: Some(metadata),
+            };
+            let t = self.create_alias_output(Some(params), None).await?;
+            // A new alias has a null id in the output, the real one is derived from its output id
+            let output_id = alias_output_id(&t, &AliasId::null())?;
+            (t, AliasId::from(&output_id), output_id)
         };
 
-        let _ = self.sync(None).await?;
-        Ok("return".to_string()) // TODO: return alias id (useful for the first time creation)
+        let mut timings = WriteTimings::default();
+        let (block_id, inclusion) = timings
+            .measure_async(Phase::InclusionWait, self.client().confirm(&submitted_block(&t)?, confirmation))
+            .await?;
+        if !inclusion.satisfies(&confirmation) {
+            log::warn!("Block {block_id} is {inclusion}");
+        }
+        let explorer_url = config.explorer_block_url(block_id);
+        if let Some(url) = &explorer_url {
+            log::info!("Block on Explorer: {url}");
+        }
+        let _ = timings.measure_async(Phase::Sync, self.sync(None)).await?;
+        log::info!("Finished write_alias_data in {:.2?}", write_alias_data_start_time.elapsed());
+
+        Ok(WriteReceipt {
+            transaction_id: t.transaction_id,
+            block_id,
+            output_ids: vec![output_id],
+            storage_deposit: t.payload.essence().as_regular().outputs()[output_id.index() as usize].amount(),
+            payload_len,
+            encoded_len: payload_len,
+            inclusion,
+            timings,
+            explorer_url,
+            alias_id: Some(alias_id),
+        })
     }
-}
\ No newline at end of file
+
+    async fn write_data_batch(
+        &self,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        entries: Vec<BatchEntry>,
+    ) -> Result<Vec<WriteReceipt>> {
+        log::info!("Start write_data_batch");
+        let start = Instant::now();
+        let receipts = batch::write_batch(self, config, address, entries).await?;
+        log::info!("Finished write_data_batch of {} records in {:.2?}", receipts.len(), start.elapsed());
+        Ok(receipts)
+    }
+
+    async fn estimate_write_data(
+        &self,
+        address: &Bech32Address,
+        tag: &str,
+        metadata: &[u8],
+        options: &WriteOptions,
+    ) -> Result<WriteEstimate> {
+        let estimate = estimate::estimate_write(self.client(), WritePath::Account, address, tag, metadata, options).await?;
+        let available = self.balance().await?.base_coin().available();
+        Ok(estimate.with_available(available))
+    }
+
+    async fn sweep_data_outputs(
+        &self,
+        options: &SweepOptions,
+        archive: Option<&PurityStorage>,
+    ) -> Result<SweepReport> {
+        sweep::sweep(self, options, archive).await
+    }
+}
+
+// Writes the request with the account, fragmenting large payloads
+pub(super) async fn write_request(account: &Account, config: &PurityConfig, request: WriteRequest) -> Result<WriteReceipt> {
+    let WriteRequest { address, tag, payload, options } = request;
+    let payload_len = payload.len();
+    // Signing and encryption come before fragmentation, chunks carry pieces of the envelope
+    let metadata = options.encode(&*account.get_secret_manager().read().await, tag.as_bytes(), payload).await?;
+    write_encoded(account, config, &address, &tag, metadata, payload_len, options).await
+}
+
+// Writes `metadata`, already encoded from a payload of `payload_len` bytes, fragmenting it if needed
+#[tracing::instrument(name = "write", skip_all, fields(path = "account", tag = %tag, payload_len = payload_len))]
+pub(super) async fn write_encoded(
+    account: &Account,
+    config: &PurityConfig,
+    address: &Bech32Address,
+    tag: &str,
+    metadata: Vec<u8>,
+    payload_len: usize,
+    options: WriteOptions,
+) -> Result<WriteReceipt> {
+    log::info!("Start write_data");
+    let write_data_start_time = Instant::now();
+    let mut timings = WriteTimings::default();
+    let len_metadata = payload_len;
+    let encoded_len = metadata.len();
+    // Send native tokens together with the required storage deposit
+    let rent_structure = account.client().get_rent_structure().await?;
+    let token_supply = account.client().get_token_supply().await?;
+
+    // Payloads larger than a MetadataFeature are written as chunks, the data output becomes their manifest
+    let mut output_ids = Vec::new();
+    let mut storage_deposit = 0;
+    let metadata = if fragment::needs_fragmentation(&metadata) {
+        let chunks = fragment::split(&metadata);
+        if chunks.len() > fragment::MAX_CHUNKS {
+            log::warn!("metadata of {} B needs {} chunks, at most {} are supported", len_metadata, chunks.len(), fragment::MAX_CHUNKS);
+            return Err(PurityError::MetadataTooLarge(None));
+        }
+        log::info!("Fragmenting metadata in {} chunks", chunks.len());
+
+        for batch in chunks.chunks(fragment::CHUNKS_PER_TRANSACTION) {
+            let chunk_outputs = timings.measure(Phase::OutputBuild, || {
+                batch
+                    .iter()
+                    .map(|chunk| chunk_output(address, chunk.clone(), &options, rent_structure, token_supply))
+                    .collect::<Result<Vec<_>>>()
+            })?;
+            let t = send_timed(account, chunk_outputs.clone(), &mut timings).await?;
+            let block_id = submitted_block(&t)?;
+            let (block_id, state) = timings
+                .measure_async(Phase::InclusionWait, account.client().confirm(&block_id, options.confirmation.for_chunks()))
+                .await?;
+            if !state.is_confirmed() {
+                return Err(PurityError::Unconfirmed(block_id, state));
+            }
+            output_ids.extend(find_output_ids(&t, &chunk_outputs)?);
+            storage_deposit += chunk_outputs.iter().map(Output::amount).sum::<u64>();
+            // Make the remainder of this transaction available to the next one
+            let _ = timings.measure_async(Phase::Sync, account.sync(None)).await?;
+        }
+        fragment::Manifest::new(&metadata, output_ids.clone()).to_bytes()
+    } else {
+        metadata
+    };
+
+    let output = timings.measure(Phase::OutputBuild, || {
+        data_output(address, tag, metadata, &options, rent_structure, token_supply)
+    })?;
+
+    let outputs = vec![
+        output.clone()
+    ];
+
+    let return_value = match send_timed(account, outputs, &mut timings).await {
+        Ok(t) => match submitted_block(&t) {
+            Ok(block_id) => timings
+                .measure_async(Phase::InclusionWait, account.client().confirm(&block_id, options.confirmation))
+                .await
+                .map(|(block_id, inclusion)| {
+                    if !inclusion.satisfies(&options.confirmation) {
+                        log::warn!("Block {block_id} is {inclusion}");
+                    }
+                    (t, block_id, inclusion)
+                }),
+            Err(err) => Err(err),
+        },
+        Err(err) => {
+            log::warn!("Error sending transaction: {}", err);
+            Err(err)
+        }
+    };
+
+    let _ = timings.measure_async(Phase::Sync, account.sync(None)).await?;
+    log::info!("Finished write_data in {:.2?} - metadata len: {} B", write_data_start_time.elapsed(), len_metadata);
+    timings.emit();
+    let (t, block_id, inclusion) = return_value?;
+    output_ids.extend(find_output_ids(&t, std::slice::from_ref(&output))?);
+    let explorer_url = config.explorer_block_url(block_id);
+    if let Some(url) = &explorer_url {
+        log::info!("Block on Explorer: {url}");
+    }
+    Ok(WriteReceipt {
+        transaction_id: t.transaction_id,
+        block_id,
+        output_ids,
+        storage_deposit: storage_deposit + output.amount(),
+        payload_len: len_metadata,
+        encoded_len,
+        inclusion,
+        timings,
+        explorer_url,
+        alias_id: None,
+    })
+}
+
+// Sends `outputs` like `Account::send_outputs`, timing the transaction build apart from signing and submission
+pub(super) async fn send_timed(account: &Account, outputs: Vec<Output>, timings: &mut WriteTimings) -> Result<Transaction> {
+    let prepared = timings
+        .measure_async(Phase::BlockBuild, account.prepare_transaction(outputs, None))
+        .await?;
+    Ok(timings
+        .measure_async(Phase::Submission, account.sign_and_submit_transaction(prepared, None))
+        .await?)
+}
+
+// Block carrying the transaction, missing when the node didn't accept it
+pub(super) fn submitted_block(transaction: &Transaction) -> Result<BlockId> {
+    transaction.block_id.ok_or_else(|| {
+        log::warn!("transaction {} was not submitted in a block", transaction.transaction_id);
+        PurityError::NodeUnreachable(None)
+    })
+}
+
+// Id of the output of alias `alias_id` created by the transaction, a new alias has a null id
+fn alias_output_id(transaction: &Transaction, alias_id: &AliasId) -> Result<OutputId> {
+    let index = transaction
+        .payload
+        .essence()
+        .as_regular()
+        .outputs()
+        .iter()
+        .position(|o| matches!(o, Output::Alias(a) if a.alias_id() == alias_id))
+        .ok_or_else(|| PurityError::NotFound(format!("alias output in transaction {}", transaction.transaction_id)))?;
+    Ok(OutputId::new(transaction.transaction_id, index as u16)?)
+}
+
+// Finds the ids of the data `outputs` among the outputs created by the transaction
+fn find_output_ids(transaction: &Transaction, outputs: &[Output]) -> Result<Vec<OutputId>> {
+    output_ids_of(transaction.transaction_id, transaction.payload.essence().as_regular().outputs(), outputs)
+}
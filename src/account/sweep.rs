@@ -0,0 +1,366 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reclaims the storage deposit locked in data outputs.
+//!
+//! A data output written by Purity can be swept once its timelock or expiration passed and the
+//! account can unlock it, as owner or as return address. Outputs without either condition are
+//! kept: their deposit was meant to stay. Fragmented payloads are swept as a whole, a manifest
+//! together with all of its chunks in the same transaction, so a payload is never left half
+//! readable. Content is archived before any transaction consuming it is sent.
+
+use std::collections::{HashMap, HashSet};
+
+use iota_sdk::{
+    types::block::{
+        address::Address,
+        output::{unlock_condition::AddressUnlockCondition, BasicOutputBuilder, Output, OutputId},
+        payload::transaction::TransactionId,
+    },
+    wallet::account::{types::OutputData, Account, TransactionOptions},
+};
+
+use crate::envelope;
+use crate::error::Result;
+use crate::fragment::{self, Manifest};
+use crate::storage::{ArchivedOutput, PurityStorage};
+
+// Inputs a transaction can have
+const MAX_INPUTS: usize = 128;
+
+/// Limits and mode of a sweep run.
+#[derive(Clone, Debug)]
+pub struct SweepOptions {
+    /// Only sweep the data outputs under this tag.
+    pub tag: Option<String>,
+    /// Most outputs consumed in one run.
+    pub max_outputs: usize,
+    /// Most tokens reclaimed in one run.
+    pub max_amount: Option<u64>,
+    /// Inputs of each sweep transaction, at most 128. A fragmented payload with more chunks goes
+    /// in a transaction of its own.
+    pub inputs_per_transaction: usize,
+    /// Only report what would be swept.
+    pub dry_run: bool,
+}
+
+impl Default for SweepOptions {
+    fn default() -> Self {
+        Self {
+            tag: None,
+            max_outputs: 500,
+            max_amount: None,
+            inputs_per_transaction: 100,
+            dry_run: false,
+        }
+    }
+}
+
+/// A data output consumed, or to be consumed in a dry run, by the sweeper.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SweptOutput {
+    pub output_id: OutputId,
+    pub tag: Vec<u8>,
+    pub amount: u64,
+    /// Whether its content was saved to the local store before the output was consumed.
+    pub archived: bool,
+}
+
+/// Outcome of a sweep run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SweepReport {
+    pub dry_run: bool,
+    pub swept: Vec<SweptOutput>,
+    /// Tokens returned to the wallet, or that would be in a dry run.
+    pub reclaimed: u64,
+    /// Sweepable outputs left for a later run by the limits, or because their payload needs more
+    /// inputs than a transaction can have.
+    pub deferred: usize,
+    pub transactions: Vec<TransactionId>,
+}
+
+pub(super) async fn sweep(
+    account: &Account,
+    options: &SweepOptions,
+    archive: Option<&PurityStorage>,
+) -> Result<SweepReport> {
+    let _ = account.sync(None).await?;
+    let now = account.client().get_time_checked().await?;
+    let addresses = account.addresses().await?;
+    let own: HashSet<Address> = addresses.iter().map(|a| *a.address().inner()).collect();
+
+    let outputs = account.unspent_outputs(None).await?;
+    let data_outputs: HashMap<OutputId, &OutputData> = outputs
+        .iter()
+        .filter(|o| is_data_output(&o.output, options.tag.as_deref()))
+        .map(|o| (o.output_id, o))
+        .collect();
+    let sweepable = |o: &OutputData| {
+        let unlock_conditions = o.output.unlock_conditions().expect("basic outputs have unlock conditions");
+        let owner = unlock_conditions.address().expect("basic outputs have an address").address();
+        let lapsed = unlock_conditions.expiration().is_some_and(|e| now >= e.timestamp())
+            || unlock_conditions.timelock().is_some_and(|t| now >= t.timestamp());
+        lapsed && !unlock_conditions.is_time_locked(now) && own.contains(unlock_conditions.locked_address(owner, now))
+    };
+
+    let groups = group_outputs(&data_outputs, options.tag.is_some(), sweepable);
+
+    let mut report = SweepReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    let mut selected: Vec<Vec<OutputId>> = Vec::new();
+    let mut selected_len = 0;
+    for group in groups {
+        let amount: u64 = group.iter().map(|o| o.output.amount()).sum();
+        let within_limits = selected_len + group.len() <= options.max_outputs
+            && options.max_amount.is_none_or(|max| report.reclaimed + amount <= max);
+        if !within_limits || group.len() > MAX_INPUTS {
+            report.deferred += group.len();
+            continue;
+        }
+
+        // Saved before anything is spent, the archive is keyed by output id so a rerun overwrites it
+        let archived = match archive {
+            Some(storage) if !options.dry_run => {
+                let archived = ArchivedOutput::new(group[0].output_id, tag(&group[0].output), group_data(&group)?, amount);
+                storage.archive(&archived)?;
+                true
+            }
+            _ => false,
+        };
+        for output in &group {
+            report.swept.push(SweptOutput {
+                output_id: output.output_id,
+                tag: tag(&output.output),
+                amount: output.output.amount(),
+                archived,
+            });
+        }
+        report.reclaimed += amount;
+        selected_len += group.len();
+        selected.push(group.iter().map(|o| o.output_id).collect());
+    }
+    log::info!(
+        "sweep{}: {} outputs, {} tokens, {} deferred",
+        if options.dry_run { " (dry run)" } else { "" },
+        report.swept.len(),
+        report.reclaimed,
+        report.deferred
+    );
+    if options.dry_run || selected.is_empty() {
+        return Ok(report);
+    }
+
+    let token_supply = account.client().get_token_supply().await?;
+    let return_address = *addresses[0].address().inner();
+    for batch in pack(selected, options.inputs_per_transaction) {
+        let amount: u64 = batch.iter().map(|id| data_outputs[id].output.amount()).sum();
+        let output = BasicOutputBuilder::new_with_amount(amount)
+            .add_unlock_condition(AddressUnlockCondition::new(return_address))
+            .finish_output(token_supply)?;
+        let transaction_options = TransactionOptions {
+            custom_inputs: Some(batch),
+            ..Default::default()
+        };
+        let t = account.send_outputs(vec![output], Some(transaction_options)).await?;
+        let _ = account
+            .retry_transaction_until_included(&t.transaction_id, None, None)
+            .await?;
+        report.transactions.push(t.transaction_id);
+        let _ = account.sync(None).await?;
+    }
+    Ok(report)
+}
+
+// Groups the sweepable outputs, oldest first: a manifest with all of its chunks, or a single output
+//
+// Chunks go with their manifest, chunks without one are swept on their own unless a tag filter is set
+fn group_outputs<'a>(
+    data_outputs: &HashMap<OutputId, &'a OutputData>,
+    tag_filter: bool,
+    sweepable: impl Fn(&OutputData) -> bool,
+) -> Vec<Vec<&'a OutputData>> {
+    let mut groups: Vec<Vec<&OutputData>> = Vec::new();
+    let mut referenced = HashSet::new();
+    for output in data_outputs.values() {
+        let data = metadata(&output.output);
+        if !fragment::is_manifest(data) {
+            continue;
+        }
+        let Ok(manifest) = Manifest::from_bytes(data) else { continue };
+        referenced.extend(manifest.chunks.iter().copied());
+        if !sweepable(output) {
+            continue;
+        }
+        let chunks: Option<Vec<&OutputData>> = manifest.chunks.iter().map(|id| data_outputs.get(id).copied()).collect();
+        match chunks {
+            Some(chunks) if chunks.iter().all(|c| sweepable(c)) => {
+                let mut group = vec![*output];
+                group.extend(chunks);
+                groups.push(group);
+            }
+            _ => log::debug!("manifest {} kept, some of its chunks can't be swept yet", output.output_id),
+        }
+    }
+    for output in data_outputs.values() {
+        let data = metadata(&output.output);
+        let chunk = fragment::is_chunk(data);
+        let chunk_of_manifest = chunk && referenced.contains(&output.output_id);
+        let foreign_chunk = chunk && tag_filter;
+        if !fragment::is_manifest(data) && !chunk_of_manifest && !foreign_chunk && sweepable(output) {
+            groups.push(vec![*output]);
+        }
+    }
+    groups.sort_by_key(|g| (g[0].metadata.milestone_timestamp_booked(), g[0].output_id));
+    groups
+}
+
+// Packs whole groups in transactions of at most `inputs_per_transaction` inputs, a larger group
+// goes alone; groups never span two transactions
+fn pack(groups: Vec<Vec<OutputId>>, inputs_per_transaction: usize) -> Vec<Vec<OutputId>> {
+    let limit = inputs_per_transaction.clamp(1, MAX_INPUTS);
+    let mut batches: Vec<Vec<OutputId>> = Vec::new();
+    for group in groups {
+        match batches.last_mut() {
+            Some(batch) if batch.len() + group.len() <= limit => batch.extend(group),
+            _ => batches.push(group),
+        }
+    }
+    batches
+}
+
+// Basic outputs carrying Purity data under a tag, or chunks, without native tokens nor deposit to give back
+//
+// Chunks carry no tag, with a filter only those referenced by a manifest of the tag are swept
+fn is_data_output(output: &Output, tag_filter: Option<&str>) -> bool {
+    let Output::Basic(basic) = output else { return false };
+    let features = basic.features();
+    let Some(metadata) = features.metadata() else { return false };
+    if envelope::parse(metadata.data()).is_none() {
+        return false;
+    }
+    let tagged = match features.tag() {
+        Some(tag) => tag_filter.is_none_or(|filter| tag.tag() == filter.as_bytes()),
+        None => fragment::is_chunk(metadata.data()),
+    };
+    tagged && basic.native_tokens().is_empty() && basic.unlock_conditions().storage_deposit_return().is_none()
+}
+
+fn metadata(output: &Output) -> &[u8] {
+    output.features().and_then(|f| f.metadata()).map(|m| m.data()).unwrap_or_default()
+}
+
+fn tag(output: &Output) -> Vec<u8> {
+    output.features().and_then(|f| f.tag()).map(|t| t.tag().to_vec()).unwrap_or_default()
+}
+
+// Data of the group leader, rebuilt from the chunks in the group when it is a manifest
+fn group_data(group: &[&OutputData]) -> Result<Vec<u8>> {
+    let data = metadata(&group[0].output);
+    if !fragment::is_manifest(data) {
+        return Ok(data.to_vec());
+    }
+    let manifest = Manifest::from_bytes(data)?;
+    let by_id: HashMap<OutputId, &[u8]> = group[1..].iter().map(|o| (o.output_id, metadata(&o.output))).collect();
+    let chunks = manifest.chunks.iter().map(|id| by_id[id].to_vec()).collect::<Vec<_>>();
+    manifest.reassemble(&chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::types::block::{
+        address::{Ed25519Address, ToBech32Ext},
+        output::{OutputMetadata, RentStructure},
+        BlockId,
+    };
+
+    use super::*;
+    use crate::ledger::SHIMMER_TOKEN_SUPPLY;
+    use crate::options::WriteOptions;
+    use crate::writer::{chunk_output, data_output};
+
+    fn output_id(index: u16) -> OutputId {
+        OutputId::new(TransactionId::new([index as u8; 32]), index).unwrap()
+    }
+
+    fn output_data(output_id: OutputId, output: Output, booked: u32) -> OutputData {
+        let metadata = OutputMetadata::new(BlockId::new([0; 32]), output_id, false, None, None, None, booked, booked, booked);
+        OutputData {
+            output_id,
+            metadata,
+            output,
+            is_spent: false,
+            address: Address::Ed25519(Ed25519Address::new([1; 32])),
+            network_id: 0,
+            remainder: false,
+            chain: None,
+        }
+    }
+
+    // A plain output, a manifest with two chunks and an orphan chunk
+    fn wallet() -> Vec<OutputData> {
+        let address = Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr");
+        let (options, rent) = (WriteOptions::default(), RentStructure::default());
+        let payload = vec![7; 2 * fragment::CHUNK_DATA_LEN];
+        let chunks = fragment::split(&payload);
+        let manifest = Manifest::new(&payload, vec![output_id(2), output_id(3)]).to_bytes();
+        let chunk = |i: usize| chunk_output(&address, chunks[i].clone(), &options, rent, SHIMMER_TOKEN_SUPPLY).unwrap();
+        vec![
+            output_data(output_id(0), data_output(&address, "t", b"data".to_vec(), &options, rent, SHIMMER_TOKEN_SUPPLY).unwrap(), 5),
+            output_data(output_id(1), data_output(&address, "t", manifest, &options, rent, SHIMMER_TOKEN_SUPPLY).unwrap(), 1),
+            output_data(output_id(2), chunk(0), 1),
+            output_data(output_id(3), chunk(1), 1),
+            output_data(output_id(4), chunk(0), 3),
+        ]
+    }
+
+    fn ids(groups: &[Vec<&OutputData>]) -> Vec<Vec<u16>> {
+        groups.iter().map(|g| g.iter().map(|o| o.output_id.index()).collect()).collect()
+    }
+
+    #[test]
+    fn manifest_is_grouped_with_its_chunks() {
+        let wallet = wallet();
+        let data_outputs: HashMap<OutputId, &OutputData> = wallet.iter().map(|o| (o.output_id, o)).collect();
+
+        let groups = group_outputs(&data_outputs, false, |_| true);
+        assert_eq!(ids(&groups), [vec![1, 2, 3], vec![4], vec![0]]);
+        // Chunks that can't be tied to the tag are left alone
+        let groups = group_outputs(&data_outputs, true, |_| true);
+        assert_eq!(ids(&groups), [vec![1, 2, 3], vec![0]]);
+    }
+
+    #[test]
+    fn manifest_waits_for_all_of_its_chunks() {
+        let wallet = wallet();
+        let data_outputs: HashMap<OutputId, &OutputData> = wallet.iter().map(|o| (o.output_id, o)).collect();
+
+        let groups = group_outputs(&data_outputs, false, |o| o.output_id != output_id(3));
+        assert_eq!(ids(&groups), [vec![4], vec![0]]);
+    }
+
+    #[test]
+    fn groups_are_never_split_across_transactions() {
+        let groups = || vec![vec![output_id(0)], vec![output_id(1), output_id(2), output_id(3)], vec![output_id(4)]];
+        let indexes = |batches: Vec<Vec<OutputId>>| -> Vec<Vec<u16>> {
+            batches.iter().map(|b| b.iter().map(OutputId::index).collect()).collect()
+        };
+        assert_eq!(indexes(pack(groups(), 4)), [vec![0, 1, 2, 3], vec![4]]);
+        assert_eq!(indexes(pack(groups(), 3)), [vec![0], vec![1, 2, 3], vec![4]]);
+        // A group larger than the limit goes alone
+        assert_eq!(indexes(pack(groups(), 2)), [vec![0], vec![1, 2, 3], vec![4]]);
+        assert_eq!(indexes(pack(groups(), 1000)), [vec![0, 1, 2, 3, 4]]);
+    }
+}
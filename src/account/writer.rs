@@ -0,0 +1,48 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! [`PurityWriter`] signing with a wallet account.
+
+use async_trait::async_trait;
+use iota_sdk::wallet::account::Account;
+
+use crate::config::PurityConfig;
+use crate::error::Result;
+use crate::writer::{PurityWriter, WriteReceipt, WriteRequest};
+
+use super::purity_account::write_request;
+
+/// Writes with a wallet `Account`, which picks the inputs and keeps track of the outputs.
+#[derive(Clone)]
+pub struct AccountWriter {
+    account: Account,
+    config: PurityConfig,
+}
+
+impl AccountWriter {
+    pub fn new(account: Account, config: PurityConfig) -> Self {
+        Self { account, config }
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+}
+
+#[async_trait]
+impl PurityWriter for AccountWriter {
+    async fn write(&self, request: WriteRequest) -> Result<WriteReceipt> {
+        write_request(&self.account, &self.config, request).await
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmark of the write paths.
+//!
+//! A [`Workload`] is run through any [`PurityWriter`]: a wallet account, a client against a node
+//! or a client against the [`SimulatedLedger`](crate::ledger::SimulatedLedger). The report keeps
+//! every sample and summarises the latency of each payload size with percentiles, as CSV or JSON.
+
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, StreamExt};
+use iota_sdk::types::block::address::Bech32Address;
+use serde::Serialize;
+
+use crate::confirmation::{Confirmation, InclusionState};
+use crate::error::Result;
+use crate::options::WriteOptions;
+use crate::writer::{PurityWriter, WriteRequest};
+
+/// Writes to run.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub tag: String,
+    /// Payload sizes in bytes, each is written `writes_per_size` times.
+    pub payload_sizes: Vec<usize>,
+    pub writes_per_size: usize,
+    /// Writes in flight at the same time.
+    pub concurrency: usize,
+    /// Options of every write, their `confirmation` wait is part of the latency.
+    pub options: WriteOptions,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            tag: "purity-bench".to_string(),
+            payload_sizes: vec![16, 1024, 8000],
+            writes_per_size: 10,
+            concurrency: 1,
+            options: WriteOptions::default().with_confirmation(Confirmation::None),
+        }
+    }
+}
+
+/// A single write of the run, durations in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    pub payload_len: usize,
+    pub latency_ms: f64,
+    pub output_build_ms: f64,
+    pub block_build_ms: f64,
+    pub submission_ms: f64,
+    pub inclusion_wait_ms: f64,
+    pub sync_ms: f64,
+    /// State of the block when the write returned.
+    pub inclusion: Option<InclusionState>,
+    /// Why the write failed, its latency is then the time to fail.
+    pub error: Option<String>,
+}
+
+/// Latency of the successful writes of a payload size, in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct Summary {
+    pub payload_len: usize,
+    pub writes: usize,
+    pub errors: usize,
+    /// Successful writes whose block didn't reach the awaited state.
+    pub unconfirmed: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    /// Successful writes per second over the time spent on this size.
+    pub throughput: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchReport {
+    pub concurrency: usize,
+    pub confirmation: String,
+    pub summaries: Vec<Summary>,
+    pub samples: Vec<Sample>,
+}
+
+impl BenchReport {
+    /// The summaries as CSV, one row per payload size.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("payload_len,writes,errors,unconfirmed,min_ms,mean_ms,p50_ms,p90_ms,p99_ms,max_ms,throughput\n");
+        for s in &self.summaries {
+            csv.push_str(&format!(
+                "{},{},{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
+                s.payload_len, s.writes, s.errors, s.unconfirmed, s.min_ms, s.mean_ms, s.p50_ms, s.p90_ms, s.p99_ms, s.max_ms, s.throughput
+            ));
+        }
+        csv
+    }
+
+    /// Every sample as CSV.
+    pub fn samples_csv(&self) -> String {
+        let mut csv = String::from(
+            "payload_len,latency_ms,output_build_ms,block_build_ms,submission_ms,inclusion_wait_ms,sync_ms,inclusion,error\n",
+        );
+        for s in &self.samples {
+            csv.push_str(&format!(
+                "{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{},{}\n",
+                s.payload_len,
+                s.latency_ms,
+                s.output_build_ms,
+                s.block_build_ms,
+                s.submission_ms,
+                s.inclusion_wait_ms,
+                s.sync_ms,
+                s.inclusion.map(|i| i.as_str()).unwrap_or_default(),
+                s.error.as_deref().unwrap_or_default().replace([',', '\n'], " ")
+            ));
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Runs `workload` through `writer` to `address`.
+pub async fn run(writer: &dyn PurityWriter, address: Bech32Address, workload: &Workload) -> BenchReport {
+    let confirmation = workload.options.confirmation;
+    let mut report = BenchReport {
+        concurrency: workload.concurrency.max(1),
+        confirmation: confirmation.to_string(),
+        summaries: Vec::new(),
+        samples: Vec::new(),
+    };
+
+    for &payload_len in &workload.payload_sizes {
+        log::info!("bench: {} writes of {} B", workload.writes_per_size, payload_len);
+        let start = Instant::now();
+        let samples: Vec<Sample> = stream::iter(0..workload.writes_per_size)
+            .map(|_| {
+                let payload = (0..payload_len).map(|_| rand::random::<u8>()).collect();
+                let request = WriteRequest::new(address, &workload.tag, payload, workload.options.clone());
+                write_sample(writer, request)
+            })
+            .buffer_unordered(report.concurrency)
+            .collect()
+            .await;
+        report.summaries.push(summarise(payload_len, &samples, &confirmation, start.elapsed()));
+        report.samples.extend(samples);
+    }
+    report
+}
+
+async fn write_sample(writer: &dyn PurityWriter, request: WriteRequest) -> Sample {
+    let payload_len = request.payload.len();
+    let start = Instant::now();
+    let result = writer.write(request).await;
+    let latency_ms = ms(start.elapsed());
+
+    match result {
+        Ok(receipt) => Sample {
+            payload_len,
+            latency_ms,
+            output_build_ms: ms(receipt.timings.output_build),
+            block_build_ms: ms(receipt.timings.block_build),
+            submission_ms: ms(receipt.timings.submission),
+            inclusion_wait_ms: ms(receipt.timings.inclusion_wait),
+            sync_ms: ms(receipt.timings.sync),
+            inclusion: Some(receipt.inclusion),
+            error: None,
+        },
+        Err(e) => {
+            log::warn!("bench: write of {payload_len} B failed: {e}");
+            Sample {
+                payload_len,
+                latency_ms,
+                output_build_ms: 0.0,
+                block_build_ms: 0.0,
+                submission_ms: 0.0,
+                inclusion_wait_ms: 0.0,
+                sync_ms: 0.0,
+                inclusion: None,
+                error: Some(e.to_string()),
+            }
+        }
+    }
+}
+
+fn summarise(payload_len: usize, samples: &[Sample], confirmation: &Confirmation, elapsed: Duration) -> Summary {
+    let mut latencies: Vec<f64> = samples.iter().filter(|s| s.error.is_none()).map(|s| s.latency_ms).collect();
+    latencies.sort_by(f64::total_cmp);
+    let ok = latencies.len();
+    Summary {
+        payload_len,
+        writes: samples.len(),
+        errors: samples.len() - ok,
+        unconfirmed: samples
+            .iter()
+            .filter(|s| s.inclusion.is_some_and(|i| !i.satisfies(confirmation)))
+            .count(),
+        min_ms: latencies.first().copied().unwrap_or_default(),
+        mean_ms: if ok == 0 { 0.0 } else { latencies.iter().sum::<f64>() / ok as f64 },
+        p50_ms: percentile(&latencies, 50.0),
+        p90_ms: percentile(&latencies, 90.0),
+        p99_ms: percentile(&latencies, 99.0),
+        max_ms: latencies.last().copied().unwrap_or_default(),
+        throughput: ok as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+    }
+}
+
+// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,595 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `purity` command line: writes and reads data on the ledger, printing JSON.
+//!
+//! The configuration is read from `--config` or from the environment (see `.env.example`).
+//! Account commands use the wallet of the configuration, `--client` writes with a bare
+//! secret manager instead.
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use iota_sdk::client::{api::GetAddressesOptions, Client};
+use iota_sdk::types::block::{address::Bech32Address, output::{AliasId, OutputId}};
+use iota_sdk::wallet::account::Account;
+use serde_json::{json, Value};
+
+use purity::account::{AccountWriter, PurityAccountExt};
+use purity::bench::{self, BenchReport, Workload};
+use purity::client::{self, ClientWriter, Subscription, SubscriptionOptions};
+use purity::compression::Compression;
+use purity::estimate::WriteEstimate;
+use purity::ledger::SimulatedLedger;
+use purity::policy::UnlockPolicy;
+use purity::record::PurityRecord;
+use purity::secret::{create_secret_manager, SecretSource};
+use purity::utils::{create_or_recover_wallet, get_address_balance, request_faucet_funds};
+use purity::{Confirmation, PurityConfig, PurityError, PurityWriter, WriteOptions, WriteRequest};
+
+// The helpers return a boxed `PurityError`, it is large and errors only end the command
+type Result<T> = std::result::Result<T, CliError>;
+
+struct CliError(Box<PurityError>);
+
+impl<E: Into<PurityError>> From<E> for CliError {
+    fn from(error: E) -> Self {
+        Self(Box::new(error.into()))
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "purity", version, about = "Write and read data on the Shimmer ledger")]
+struct Cli {
+    /// TOML configuration, the environment is used when missing
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Wallet account of the account commands
+    #[arg(long, global = true, default_value = "Alice")]
+    account: String,
+    /// Print JSON on a single line
+    #[arg(long, global = true)]
+    compact: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a payload under a tag
+    Write(WriteArgs),
+    /// Read the records under a tag, or the data of an output
+    Read(ReadArgs),
+    /// Print the new records under a tag as they arrive, one JSON object per line
+    ///
+    /// A record that can't be read is printed as an `{"error": ...}` line and watching goes on.
+    Watch(WatchArgs),
+    /// Balance of the account, or of an address
+    Balance {
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Addresses of the account
+    Addresses {
+        /// Generate this many new addresses first
+        #[arg(long, default_value_t = 0)]
+        generate: u32,
+    },
+    /// Request funds from the faucet
+    Faucet {
+        /// Address to fund, the first address of the account by default
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Create or update an alias carrying data
+    #[command(subcommand)]
+    Alias(AliasCommand),
+    /// Cost of a write, without sending anything
+    Estimate(WriteArgs),
+    /// Time many writes and print latency percentiles
+    Bench(BenchArgs),
+}
+
+#[derive(Subcommand)]
+enum AliasCommand {
+    /// Create an alias, the tag becomes its immutable metadata
+    Create {
+        #[command(flatten)]
+        payload: PayloadArgs,
+        #[command(flatten)]
+        confirm: ConfirmArgs,
+    },
+    /// Move an alias to a new state carrying the payload
+    Update {
+        #[arg(long)]
+        alias_id: String,
+        #[command(flatten)]
+        payload: PayloadArgs,
+        #[command(flatten)]
+        confirm: ConfirmArgs,
+    },
+}
+
+#[derive(Args)]
+struct PayloadArgs {
+    #[arg(long)]
+    tag: String,
+    /// File holding the payload, stdin when missing
+    #[arg(long)]
+    file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct WriteArgs {
+    #[command(flatten)]
+    payload: PayloadArgs,
+    /// Address unlocking the data output, the first address of the signer by default
+    #[arg(long)]
+    address: Option<String>,
+    /// Sign with the secret manager of the configuration instead of the wallet
+    #[arg(long)]
+    client: bool,
+    /// Deflate the payload when it gets smaller
+    #[arg(long)]
+    compress: bool,
+    /// Add a sender feature
+    #[arg(long)]
+    sender: bool,
+    /// Lock the output for this many seconds
+    #[arg(long)]
+    timelock: Option<u64>,
+    #[command(flatten)]
+    confirm: ConfirmArgs,
+}
+
+#[derive(Args)]
+struct ConfirmArgs {
+    /// What to wait for once the block is sent
+    #[arg(long, value_enum, default_value_t = ConfirmArg::Milestone)]
+    confirm: ConfirmArg,
+    /// Seconds to wait for the confirmation
+    #[arg(long, default_value_t = 60)]
+    timeout: u64,
+}
+
+impl ConfirmArgs {
+    fn confirmation(&self) -> Confirmation {
+        confirmation(self.confirm, self.timeout)
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConfirmArg {
+    /// Return once the block is sent
+    None,
+    /// Wait until the block is attached to the tangle
+    Inclusion,
+    /// Wait until a milestone references the block
+    Milestone,
+}
+
+#[derive(Args)]
+struct ReadArgs {
+    #[arg(long, required_unless_present = "output_id")]
+    tag: Option<String>,
+    #[arg(long, conflicts_with = "output_id")]
+    address: Option<String>,
+    /// Read the data of this output only
+    #[arg(long)]
+    output_id: Option<String>,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    #[arg(long)]
+    tag: String,
+    #[arg(long)]
+    address: Option<String>,
+    /// Skip the records already on the ledger
+    #[arg(long)]
+    new_only: bool,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Signer and ledger of the writes
+    #[arg(long, value_enum, default_value_t = BenchTarget::Simulated)]
+    target: BenchTarget,
+    /// Payload sizes in bytes
+    #[arg(long, value_delimiter = ',', default_values_t = [16, 1024, 8000])]
+    sizes: Vec<usize>,
+    /// Writes of each size
+    #[arg(long, default_value_t = 10)]
+    writes: usize,
+    /// Writes in flight at the same time
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+    /// What each write waits for, part of its latency
+    #[arg(long, value_enum, default_value_t = ConfirmArg::None)]
+    confirm: ConfirmArg,
+    /// Seconds to wait for the confirmation
+    #[arg(long, default_value_t = 60)]
+    timeout: u64,
+    #[arg(long, default_value = "purity-bench")]
+    tag: String,
+    /// Deflate the payloads when they get smaller
+    #[arg(long)]
+    compress: bool,
+    #[arg(long, value_enum, default_value_t = BenchFormat::Json)]
+    format: BenchFormat,
+    /// With CSV, print every sample instead of the summaries
+    #[arg(long)]
+    samples: bool,
+    /// Write the report to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BenchTarget {
+    /// Wallet account on the node of the configuration
+    Account,
+    /// Secret manager of the configuration on its node
+    Client,
+    /// In-memory ledger, no node or configuration needed
+    Simulated,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BenchFormat {
+    Json,
+    Csv,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    pretty_env_logger::init();
+    let cli = Cli::parse();
+    match run(&cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError(e)) => {
+            eprintln!("{}", error_json(&e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: &Cli) -> Result<()> {
+    if let Command::Bench(args) = &cli.command {
+        return run_bench(cli, args).await;
+    }
+    let config = load_config(cli)?;
+    let print = |value: Value| print_json(&value, cli.compact);
+
+    match &cli.command {
+        Command::Write(args) => {
+            let payload = read_payload(&args.payload)?;
+            let options = write_options(args)?;
+            let receipt = if args.client {
+                let (secret_manager, client, own_address) = client_signer(&config).await?;
+                let address = address_or(args.address.as_deref(), own_address)?;
+                let writer = ClientWriter::new(client, secret_manager, config.clone());
+                writer.write(WriteRequest::new(address, &args.payload.tag, payload, options)).await?
+            } else {
+                let account = account(&config, &cli.account).await?;
+                let address = address_or(args.address.as_deref(), first_address(&account).await?)?;
+                let writer = AccountWriter::new(account, config.clone());
+                writer.write(WriteRequest::new(address, &args.payload.tag, payload, options)).await?
+            };
+            print(serde_json::to_value(&receipt)?);
+            // The receipt is printed either way, but an unconfirmed write is a failure
+            receipt.confirmed(&args.confirm.confirmation())?;
+        }
+        Command::Read(args) => {
+            let client = node_client(&config).await?;
+            if let Some(output_id) = &args.output_id {
+                let output_id = OutputId::from_str(output_id)?;
+                let data = client::read_data(&client, output_id).await?;
+                print(json!({ "output_id": output_id.to_string(), "data": data_json(&data) }));
+            } else {
+                let tag = args.tag.as_deref().expect("clap requires a tag without an output id");
+                let address = args.address.as_deref().map(Bech32Address::try_from_str).transpose()?;
+                let records = client::read_records(&client, tag, address).await?;
+                print(Value::Array(records.iter().map(record_json).collect()));
+            }
+        }
+        Command::Watch(args) => {
+            let client = node_client(&config).await?;
+            let subscription = match &args.address {
+                Some(address) => Subscription::TagAndAddress(args.tag.clone(), Bech32Address::try_from_str(address)?),
+                None => Subscription::Tag(args.tag.clone()),
+            };
+            let options = SubscriptionOptions {
+                include_existing: !args.new_only,
+                ..Default::default()
+            };
+            let mut records = client::subscribe(&client, subscription, options).await;
+            while let Some(record) = records.next().await {
+                match record {
+                    Ok(record) => print_json(&record_json(&record), true),
+                    Err(e) => print_json(&error_json(&e), true),
+                }
+            }
+        }
+        Command::Balance { address: Some(address) } => {
+            let client = node_client(&config).await?;
+            let address = Bech32Address::try_from_str(address)?;
+            let amount = get_address_balance(&client, &address).await?;
+            print(json!({ "address": address.to_string(), "amount": amount }));
+        }
+        Command::Balance { address: None } => {
+            let account = account(&config, &cli.account).await?;
+            let balance = account.sync(None).await?;
+            print(json!({
+                "account": cli.account,
+                "total": balance.base_coin().total(),
+                "available": balance.base_coin().available(),
+                "required_storage_deposit": serde_json::to_value(balance.required_storage_deposit())?,
+            }));
+        }
+        Command::Addresses { generate } => {
+            let account = account(&config, &cli.account).await?;
+            if *generate > 0 {
+                let _ = account.generate_ed25519_addresses(*generate, None).await?;
+            }
+            print(serde_json::to_value(account.addresses().await?)?);
+        }
+        Command::Faucet { address } => {
+            let address = match address {
+                Some(address) => Bech32Address::try_from_str(address)?,
+                None => first_address(&account(&config, &cli.account).await?).await?,
+            };
+            let client = node_client(&config).await?;
+            request_faucet_funds(&client, &address, config.faucet_url()?).await?;
+            let amount = get_address_balance(&client, &address).await?;
+            print(json!({ "address": address.to_string(), "amount": amount }));
+        }
+        Command::Alias(command) => {
+            let account = account(&config, &cli.account).await?;
+            let address = first_address(&account).await?;
+            let (payload, confirm, alias_id) = match command {
+                AliasCommand::Create { payload, confirm } => (payload, confirm, None),
+                AliasCommand::Update { alias_id, payload, confirm } => (payload, confirm, Some(AliasId::from_str(alias_id)?)),
+            };
+            let data = read_payload(payload)?;
+            let confirmation = confirm.confirmation();
+            let receipt = account
+                .write_alias_data(&config, &address, payload.tag.clone().into_bytes(), data, alias_id, confirmation)
+                .await?;
+            print(serde_json::to_value(&receipt)?);
+            // Like a write, an unconfirmed alias transition is a failure
+            receipt.confirmed(&confirmation)?;
+        }
+        Command::Estimate(args) => {
+            let payload = read_payload(&args.payload)?;
+            let options = write_options(args)?;
+            let estimate = if args.client {
+                let (_, client, own_address) = client_signer(&config).await?;
+                let address = address_or(args.address.as_deref(), own_address)?;
+                let metadata = String::from_utf8(payload)
+                    .map_err(|_| PurityError::InvalidInput("the client path writes UTF-8 payloads".to_string()))?;
+                client::estimate_with_client(&client, address, &args.payload.tag, &metadata, &options).await?
+            } else {
+                let account = account(&config, &cli.account).await?;
+                let address = address_or(args.address.as_deref(), first_address(&account).await?)?;
+                account.estimate_write_data(&address, &args.payload.tag, &payload, &options).await?
+            };
+            print(estimate_json(&estimate));
+        }
+        Command::Bench(_) => unreachable!("handled before loading the configuration"),
+    }
+    Ok(())
+}
+
+fn load_config(cli: &Cli) -> Result<PurityConfig> {
+    match &cli.config {
+        Some(path) => Ok(PurityConfig::from_toml_file(path)?),
+        None => {
+            dotenv::dotenv().ok();
+            Ok(PurityConfig::from_env()?)
+        }
+    }
+}
+
+async fn run_bench(cli: &Cli, args: &BenchArgs) -> Result<()> {
+    let compression = if args.compress { Compression::DeflateIfSmaller } else { Compression::None };
+    let workload = Workload {
+        tag: args.tag.clone(),
+        payload_sizes: args.sizes.clone(),
+        writes_per_size: args.writes,
+        concurrency: args.concurrency,
+        options: WriteOptions::default()
+            .with_compression(compression)
+            .with_confirmation(confirmation(args.confirm, args.timeout)),
+    };
+
+    let report = match args.target {
+        BenchTarget::Account => {
+            let config = load_config(cli)?;
+            let account = account(&config, &cli.account).await?;
+            let address = first_address(&account).await?;
+            let writer = AccountWriter::new(account, config);
+            bench::run(&writer, address, &workload).await
+        }
+        BenchTarget::Client => {
+            let config = load_config(cli)?;
+            let (secret_manager, client, address) = client_signer(&config).await?;
+            let writer = ClientWriter::new(client, secret_manager, config);
+            bench::run(&writer, address, &workload).await
+        }
+        BenchTarget::Simulated => {
+            // Configuration only for its secrets, a throwaway seed otherwise
+            let (config, source) = match load_config(cli) {
+                Ok(config) => {
+                    let source = SecretSource::from_config(&config)?;
+                    (config, source)
+                }
+                Err(_) => (
+                    PurityConfig::builder().with_node_url("simulated").finish()?,
+                    SecretSource::HexSeed(format!("0x{}", hex::encode(rand::random::<[u8; 32]>()))),
+                ),
+            };
+            let secret_manager = create_secret_manager(source).await?;
+            let address = secret_manager
+                .generate_ed25519_addresses(GetAddressesOptions::default().with_range(0..1))
+                .await?[0];
+            let writer = ClientWriter::new(SimulatedLedger::new(), secret_manager, config);
+            bench::run(&writer, address, &workload).await
+        }
+    };
+
+    let text = bench_text(&report, args, cli.compact)?;
+    match &args.output {
+        Some(path) => std::fs::write(path, text)
+            .map_err(|e| PurityError::InvalidInput(format!("can't write {}: {e}", path.display())))?,
+        None => print!("{text}"),
+    }
+    Ok(())
+}
+
+fn bench_text(report: &BenchReport, args: &BenchArgs, compact: bool) -> Result<String> {
+    Ok(match args.format {
+        BenchFormat::Csv if args.samples => report.samples_csv(),
+        BenchFormat::Csv => report.to_csv(),
+        BenchFormat::Json if compact => serde_json::to_string(report)? + "\n",
+        BenchFormat::Json => report.to_json()? + "\n",
+    })
+}
+
+fn print_json(value: &Value, compact: bool) {
+    let json = if compact {
+        serde_json::to_string(value)
+    } else {
+        serde_json::to_string_pretty(value)
+    };
+    println!("{}", json.expect("JSON values always serialize"));
+}
+
+fn read_payload(args: &PayloadArgs) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let read = match &args.file {
+        Some(path) => std::fs::File::open(path).and_then(|mut file| file.read_to_end(&mut payload)),
+        None => std::io::stdin().read_to_end(&mut payload),
+    };
+    read.map_err(|e| PurityError::InvalidInput(format!("can't read the payload: {e}")))?;
+    Ok(payload)
+}
+
+fn write_options(args: &WriteArgs) -> Result<WriteOptions> {
+    let policy = match args.timelock {
+        Some(seconds) => UnlockPolicy::timelock_in(Duration::from_secs(seconds))?,
+        None => UnlockPolicy::None,
+    };
+    let compression = if args.compress { Compression::DeflateIfSmaller } else { Compression::None };
+    Ok(WriteOptions::from(policy)
+        .with_compression(compression)
+        .with_sender(args.sender)
+        .with_confirmation(args.confirm.confirmation()))
+}
+
+fn confirmation(confirm: ConfirmArg, timeout: u64) -> Confirmation {
+    let timeout = Duration::from_secs(timeout);
+    match confirm {
+        ConfirmArg::None => Confirmation::None,
+        ConfirmArg::Inclusion => Confirmation::Inclusion { timeout },
+        ConfirmArg::Milestone => Confirmation::Milestone { timeout },
+    }
+}
+
+async fn node_client(config: &PurityConfig) -> Result<Client> {
+    Ok(Client::builder().with_node(&config.node_url)?.finish().await?)
+}
+
+async fn account(config: &PurityConfig, alias: &str) -> Result<Account> {
+    let wallet = create_or_recover_wallet(config).await?;
+    Ok(wallet.get_or_create_account(alias).await?)
+}
+
+async fn first_address(account: &Account) -> Result<Bech32Address> {
+    let addresses = account.addresses().await?;
+    let address = addresses.first().ok_or_else(|| PurityError::NotFound("account address".to_string()))?;
+    Ok(*address.address())
+}
+
+// Secret manager of the configuration, with a client and its first address
+async fn client_signer(config: &PurityConfig) -> Result<(iota_sdk::client::secret::SecretManager, Client, Bech32Address)> {
+    let secret_manager = create_secret_manager(SecretSource::from_config(config)?).await?;
+    let client = node_client(config).await?;
+    let addresses = secret_manager
+        .generate_ed25519_addresses(GetAddressesOptions::from_client(&client).await?.with_range(0..1))
+        .await?;
+    Ok((secret_manager, client, addresses[0]))
+}
+
+fn address_or(address: Option<&str>, default: Bech32Address) -> Result<Bech32Address> {
+    match address {
+        Some(address) => Ok(Bech32Address::try_from_str(address)?),
+        None => Ok(default),
+    }
+}
+
+// UTF-8 text as is, anything else in hex
+fn data_json(data: &[u8]) -> Value {
+    match std::str::from_utf8(data) {
+        Ok(text) => json!({ "utf8": text }),
+        Err(_) => json!({ "hex": format!("0x{}", hex::encode(data)) }),
+    }
+}
+
+// The error with its sources, on one line
+fn error_json(error: &PurityError) -> Value {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        message.push_str(&format!(": {e}"));
+        source = e.source();
+    }
+    json!({ "error": message })
+}
+
+fn record_json(record: &PurityRecord) -> Value {
+    json!({
+        "output_id": record.output_id.to_string(),
+        "block_id": record.block_id.to_string(),
+        "tag": String::from_utf8_lossy(&record.tag),
+        "data": data_json(&record.metadata),
+        "encrypted": record.is_encrypted(),
+        "sender": record.sender.map(|s| s.to_string()),
+        "publisher": record.publisher.as_ref().map(|p| json!({
+            "public_key": format!("0x{}", hex::encode(p.public_key)),
+            "verified": p.verified,
+        })),
+        "milestone_timestamp": record.milestone_timestamp,
+        "spent": record.is_spent,
+    })
+}
+
+fn estimate_json(estimate: &WriteEstimate) -> Value {
+    json!({
+        "payload_len": estimate.payload_len,
+        "encoded_len": estimate.encoded_len,
+        "outputs": estimate.outputs,
+        "transactions": estimate.transactions,
+        "storage_deposit": estimate.storage_deposit,
+        "byte_cost": estimate.byte_cost,
+        "limit_exceeded": estimate.limit_exceeded,
+        "available": estimate.available,
+        "covered": estimate.is_covered(),
+    })
+}
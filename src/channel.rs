@@ -0,0 +1,426 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ordered, hash-chained channels on top of a tag.
+//!
+//! Every message carries its sequence number and the hash of the previous message:
+//! `magic | seq (u64 LE) | previous hash (32) | payload`. The first message has sequence 0 and
+//! a zero previous hash. The reader rebuilds the chain from the records under the tag and stops
+//! at the first gap, fork or broken link, reporting it.
+//!
+//! Messages are written with the regular write APIs, so they can also be compressed and
+//! encrypted; the chain is computed on the message before those envelopes. They are always signed
+//! by the publisher, and readers only follow the messages signed by its key: anyone can write
+//! under the tag, a stranger must not be able to extend or fork the chain.
+
+use std::collections::BTreeMap;
+
+use iota_sdk::crypto::keys::bip44::Bip44;
+use iota_sdk::types::block::{address::Bech32Address, output::OutputId};
+use serde::{Deserialize, Serialize};
+
+use crate::account::PurityAccountExt;
+use crate::config::PurityConfig;
+use crate::error::Result;
+use crate::fragment::payload_hash;
+use crate::options::WriteOptions;
+use crate::record::PurityRecord;
+
+/// Magic bytes at the start of every channel message.
+pub const CHANNEL_MAGIC: [u8; 4] = *b"PCH1";
+const HEADER_LEN: usize = CHANNEL_MAGIC.len() + 8 + 32;
+
+/// Last message of a channel known to be valid, where writing or reading resumes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub seq: u64,
+    #[serde(with = "hex")]
+    pub hash: [u8; 32],
+}
+
+/// A message of the channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelMessage {
+    pub seq: u64,
+    pub prev_hash: [u8; 32],
+    /// Blake2b-256 of the whole message, referenced by the next one.
+    pub hash: [u8; 32],
+    pub payload: Vec<u8>,
+    pub output_id: Option<OutputId>,
+}
+
+impl ChannelMessage {
+    pub fn new(seq: u64, prev_hash: [u8; 32], payload: Vec<u8>) -> Self {
+        let mut message = Self {
+            seq,
+            prev_hash,
+            hash: [0; 32],
+            payload,
+            output_id: None,
+        };
+        message.hash = payload_hash(&message.to_bytes());
+        message
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.extend_from_slice(&CHANNEL_MAGIC);
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes.extend_from_slice(&self.prev_hash);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Parses a message, `None` if `bytes` is not a channel message.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[..CHANNEL_MAGIC.len()] != CHANNEL_MAGIC {
+            return None;
+        }
+        let seq = u64::from_le_bytes(bytes[CHANNEL_MAGIC.len()..CHANNEL_MAGIC.len() + 8].try_into().ok()?);
+        let prev_hash = bytes[CHANNEL_MAGIC.len() + 8..HEADER_LEN].try_into().ok()?;
+        Some(Self {
+            seq,
+            prev_hash,
+            hash: payload_hash(bytes),
+            payload: bytes[HEADER_LEN..].to_vec(),
+            output_id: None,
+        })
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            seq: self.seq,
+            hash: self.hash,
+        }
+    }
+}
+
+/// Appends messages to a channel.
+///
+/// Messages are signed with the key at `signer`, see `signature::publisher_key` for the key the
+/// readers expect. The writer state is its [`Checkpoint`], save it to resume appending after a restart.
+#[derive(Clone, Debug)]
+pub struct ChannelWriter {
+    tag: String,
+    signer: Bip44,
+    last: Option<Checkpoint>,
+}
+
+impl ChannelWriter {
+    /// Starts a new channel under `tag`.
+    pub fn new(tag: impl Into<String>, signer: Bip44) -> Self {
+        Self {
+            tag: tag.into(),
+            signer,
+            last: None,
+        }
+    }
+
+    /// Continues a channel after its last written message.
+    pub fn resume(tag: impl Into<String>, signer: Bip44, last: Checkpoint) -> Self {
+        Self {
+            tag: tag.into(),
+            signer,
+            last: Some(last),
+        }
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        self.last
+    }
+
+    /// The next message carrying `payload`, the writer doesn't move until [`ChannelWriter::commit`].
+    pub fn next_message(&self, payload: Vec<u8>) -> ChannelMessage {
+        match self.last {
+            Some(last) => ChannelMessage::new(last.seq + 1, last.hash, payload),
+            None => ChannelMessage::new(0, [0; 32], payload),
+        }
+    }
+
+    /// Records `message` as written.
+    pub fn commit(&mut self, message: &ChannelMessage) {
+        self.last = Some(message.checkpoint());
+    }
+
+    /// Writes the next message, signed, with the account and moves the writer past it.
+    ///
+    /// The writer only moves once the block satisfies the `Confirmation` of `options`, otherwise
+    /// the write fails with `PurityError::Unconfirmed` and the message can be appended again.
+    pub async fn append<A: PurityAccountExt + Sync>(
+        &mut self,
+        account: &A,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        payload: Vec<u8>,
+        options: WriteOptions,
+    ) -> Result<ChannelMessage> {
+        let mut message = self.next_message(payload);
+        let confirmation = options.confirmation;
+        let options = options.with_signer(self.signer);
+        let receipt = account
+            .write_data(config, address, &self.tag, message.to_bytes(), options, None)
+            .await?
+            .confirmed(&confirmation)?;
+        message.output_id = Some(receipt.output_id());
+        self.commit(&message);
+        Ok(message)
+    }
+}
+
+/// Anomaly found while rebuilding a channel, the chain is not followed past it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelIssue {
+    /// No message with sequence `expected`, while later ones exist.
+    Gap { expected: u64, next: u64 },
+    /// Several valid messages claim the same position.
+    Fork { seq: u64, output_ids: Vec<Option<OutputId>> },
+    /// Messages at the expected position don't link to the previous message, which was
+    /// altered or replaced.
+    BrokenLink { seq: u64, output_ids: Vec<Option<OutputId>> },
+    /// Records under the tag that are not channel messages.
+    Malformed { output_id: OutputId },
+}
+
+/// Result of rebuilding a channel.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelReport {
+    /// Verified messages after the starting checkpoint, in order.
+    pub messages: Vec<ChannelMessage>,
+    pub issues: Vec<ChannelIssue>,
+    /// Last verified message, to resume reading from.
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// Rebuilds the order of the channel messages in `records`, starting after `from`.
+///
+/// Only records signed by `publisher_key` are considered, the others are ignored. Records must be
+/// decoded, i.e. decrypted if needed, see `client::read_records_decrypted`.
+pub fn rebuild(records: Vec<PurityRecord>, from: Option<Checkpoint>, publisher_key: &[u8; 32]) -> ChannelReport {
+    let mut report = ChannelReport {
+        checkpoint: from,
+        ..Default::default()
+    };
+
+    let mut by_seq: BTreeMap<u64, Vec<ChannelMessage>> = BTreeMap::new();
+    for record in records {
+        if !record.is_authentic_from(publisher_key) {
+            log::debug!("record {} is not signed by the channel publisher", record.output_id);
+            continue;
+        }
+        match ChannelMessage::from_bytes(&record.metadata) {
+            Some(mut message) => {
+                message.output_id = Some(record.output_id);
+                let candidates = by_seq.entry(message.seq).or_default();
+                // The same message written twice is not a fork
+                if !candidates.iter().any(|c| c.hash == message.hash) {
+                    candidates.push(message);
+                }
+            }
+            None => report.issues.push(ChannelIssue::Malformed { output_id: record.output_id }),
+        }
+    }
+
+    let (mut seq, mut prev_hash) = match from {
+        Some(checkpoint) => (checkpoint.seq + 1, checkpoint.hash),
+        None => (0, [0; 32]),
+    };
+    loop {
+        let Some(candidates) = by_seq.remove(&seq) else {
+            if let Some((&next, _)) = by_seq.range(seq..).next() {
+                report.issues.push(ChannelIssue::Gap { expected: seq, next });
+            }
+            break;
+        };
+        let (mut linked, unlinked): (Vec<_>, Vec<_>) = candidates.into_iter().partition(|m| m.prev_hash == prev_hash);
+        match linked.len() {
+            1 => {
+                let message = linked.remove(0);
+                prev_hash = message.hash;
+                report.checkpoint = Some(message.checkpoint());
+                report.messages.push(message);
+                seq += 1;
+            }
+            0 => {
+                report.issues.push(ChannelIssue::BrokenLink {
+                    seq,
+                    output_ids: unlinked.iter().map(|m| m.output_id).collect(),
+                });
+                break;
+            }
+            _ => {
+                report.issues.push(ChannelIssue::Fork {
+                    seq,
+                    output_ids: linked.iter().map(|m| m.output_id).collect(),
+                });
+                break;
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::client::{constants::SHIMMER_COIN_TYPE, secret::SecretManager};
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+
+    use super::*;
+    use crate::client::decode_records;
+    use crate::ledger::{Ledger, SimulatedLedger};
+    use crate::secret::{create_secret_manager, SecretSource};
+    use crate::signature::publisher_key;
+    use crate::writer::data_output;
+
+    fn signer() -> Bip44 {
+        Bip44::new(SHIMMER_COIN_TYPE)
+    }
+
+    async fn secret_manager(seed: u8) -> SecretManager {
+        create_secret_manager(SecretSource::HexSeed(hex::encode([seed; 32]))).await.unwrap()
+    }
+
+    async fn publisher() -> [u8; 32] {
+        publisher_key(&secret_manager(5).await, signer()).await.unwrap()
+    }
+
+    // Records of `messages` signed by the secret manager of each, written in one transaction, in this order
+    async fn signed_records(messages: &[(Vec<u8>, u8)]) -> Vec<PurityRecord> {
+        let ledger = SimulatedLedger::new();
+        let (rent_structure, token_supply) = (ledger.rent_structure().await.unwrap(), ledger.token_supply().await.unwrap());
+        let address = Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr");
+        let options = WriteOptions::default().with_signer(signer());
+        let mut outputs = Vec::new();
+        for (message, seed) in messages {
+            let metadata = options.encode(&secret_manager(*seed).await, b"channel", message.clone()).await.unwrap();
+            outputs.push(data_output(&address, "channel", metadata, &options, rent_structure, token_supply).unwrap());
+        }
+        let sent = ledger.book(outputs).unwrap();
+        let output_ids = (0..messages.len() as u16).map(|i| OutputId::new(sent.transaction_id, i).unwrap()).collect();
+        decode_records(&ledger, output_ids).await.unwrap()
+    }
+
+    // Records of `messages` signed by the publisher
+    async fn records(messages: &[Vec<u8>]) -> Vec<PurityRecord> {
+        signed_records(&messages.iter().map(|m| (m.clone(), 5)).collect::<Vec<_>>()).await
+    }
+
+    // The first `count` messages of a channel
+    fn chain(count: usize) -> Vec<ChannelMessage> {
+        let mut writer = ChannelWriter::new("channel", signer());
+        (0..count)
+            .map(|i| {
+                let message = writer.next_message(format!("message {i}").into_bytes());
+                writer.commit(&message);
+                message
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn messages_are_ordered_and_resumed_from_a_checkpoint() {
+        let chain = chain(3);
+        // Written out of order, the first one twice
+        let written = [&chain[2], &chain[0], &chain[1], &chain[0]].map(ChannelMessage::to_bytes);
+        let report = rebuild(records(&written).await, None, &publisher().await);
+        assert!(report.issues.is_empty());
+        assert_eq!(report.messages.iter().map(|m| m.seq).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(report.checkpoint, Some(chain[2].checkpoint()));
+
+        let report = rebuild(records(&written).await, Some(chain[0].checkpoint()), &publisher().await);
+        assert_eq!(report.messages.iter().map(|m| m.payload.clone()).collect::<Vec<_>>(), [b"message 1", b"message 2"]);
+    }
+
+    #[tokio::test]
+    async fn gap_stops_the_chain() {
+        let chain = chain(4);
+        let written = [&chain[0], &chain[1], &chain[3]].map(ChannelMessage::to_bytes);
+        let report = rebuild(records(&written).await, None, &publisher().await);
+        assert_eq!(report.messages.len(), 2);
+        assert_eq!(report.issues, [ChannelIssue::Gap { expected: 2, next: 3 }]);
+        assert_eq!(report.checkpoint, Some(chain[1].checkpoint()));
+    }
+
+    #[tokio::test]
+    async fn fork_stops_the_chain() {
+        let chain = chain(2);
+        let other = ChannelMessage::new(1, chain[0].hash, b"other".to_vec());
+        let written = [chain[0].to_bytes(), chain[1].to_bytes(), other.to_bytes()];
+        let records = records(&written).await;
+        let forked = vec![Some(records[1].output_id), Some(records[2].output_id)];
+        let report = rebuild(records, None, &publisher().await);
+        assert_eq!(report.messages, [ChannelMessage { output_id: report.messages[0].output_id, ..chain[0].clone() }]);
+        assert_eq!(report.issues, [ChannelIssue::Fork { seq: 1, output_ids: forked }]);
+    }
+
+    #[tokio::test]
+    async fn broken_link_and_malformed_records_are_reported() {
+        let chain = chain(2);
+        // Message 0 replaced after message 1 was written
+        let replaced = ChannelMessage::new(0, [0; 32], b"replaced".to_vec());
+        let written = [replaced.to_bytes(), chain[1].to_bytes(), b"not a message".to_vec()];
+        let records = records(&written).await;
+        let (unlinked, malformed) = (records[1].output_id, records[2].output_id);
+        let report = rebuild(records, None, &publisher().await);
+        assert_eq!(report.messages.len(), 1);
+        assert_eq!(
+            report.issues,
+            [
+                ChannelIssue::Malformed { output_id: malformed },
+                ChannelIssue::BrokenLink { seq: 1, output_ids: vec![Some(unlinked)] },
+            ]
+        );
+        assert_eq!(report.checkpoint, Some(replaced.checkpoint()));
+    }
+
+    #[tokio::test]
+    async fn messages_of_a_stranger_are_ignored() {
+        let chain = chain(2);
+        // A stranger links its own message to the chain and copies a genuine one
+        let linked = ChannelMessage::new(1, chain[0].hash, b"stranger".to_vec());
+        let written = [(chain[0].to_bytes(), 5), (linked.to_bytes(), 6), (chain[1].to_bytes(), 6), (b"junk".to_vec(), 6)];
+        let records = signed_records(&written).await;
+        let report = rebuild(records.clone(), None, &publisher().await);
+        assert!(report.issues.is_empty());
+        assert_eq!(report.messages.len(), 1);
+        assert_eq!(report.checkpoint, Some(chain[0].checkpoint()));
+
+        // Read with the stranger key, the publisher messages are the ones ignored
+        let stranger = publisher_key(&secret_manager(6).await, signer()).await.unwrap();
+        let report = rebuild(records, None, &stranger);
+        assert!(report.messages.is_empty());
+        assert_eq!(report.issues.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unsigned_messages_are_ignored() {
+        let ledger = SimulatedLedger::new();
+        let address = Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr");
+        let output = data_output(
+            &address,
+            "channel",
+            chain(1)[0].to_bytes(),
+            &WriteOptions::default(),
+            ledger.rent_structure().await.unwrap(),
+            ledger.token_supply().await.unwrap(),
+        )
+        .unwrap();
+        let sent = ledger.book(vec![output]).unwrap();
+        let records = decode_records(&ledger, vec![OutputId::new(sent.transaction_id, 0).unwrap()]).await.unwrap();
+        assert_eq!(rebuild(records, None, &publisher().await), ChannelReport::default());
+    }
+}
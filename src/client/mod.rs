@@ -12,221 +12,316 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{time::Instant, env};
-use anyhow::{Context, Ok};
+pub use subscription::{
+    subscribe, CancelHandle, EventSource, LedgerQuery, RecordQuery, RecordStream, SourceEvent, Subscription, SubscriptionOptions,
+};
+#[cfg(feature = "mqtt")]
+pub use subscription::MqttEventSource;
+
+pub use pagination::{read_all_pages, read_page, read_pages, PageOptions};
+pub use writer::ClientWriter;
+
+mod pagination;
+mod subscription;
+mod writer;
+
+use tracing::{info_span, Instrument};
 
 use iota_sdk::{
     types::block::{
-        address::Bech32Address,
-        output::{
-            feature::{TagFeature, MetadataFeature, SenderFeature},
-            unlock_condition::{AddressUnlockCondition, ExpirationUnlockCondition, UnlockCondition},
-            BasicOutputBuilder, Feature, OutputId, OutputWithMetadata, 
-        }, 
-        BlockId
+        address::{Bech32Address, Hrp},
+        output::{OutputId, OutputWithMetadata}, 
     },
     client::{ 
         Client, 
-        utils::request_funds_from_faucet, 
         secret::SecretManager,
-        node_api::indexer::query_parameters::QueryParameter, api::GetAddressesOptions
+        api::GetAddressesOptions
     }
 };
 
-use crate::utils::request_faucet_funds;
-
-pub async fn setup_with_client() -> anyhow::Result<(SecretManager, Client, Bech32Address)> {
-    let mut start;
-    let mut duration;
-    
-    println!("IOTA channel tests\n\n");
-
-    start = Instant::now();
-    let client = Client::builder().with_node(&env::var("NODE_URL").unwrap())?.finish().await?;
-    duration = start.elapsed().as_millis();
-    println!("Time elapsed in Client::builder() is: {:?}", duration );
+use crate::channel::{self, ChannelReport, Checkpoint};
+use crate::config::PurityConfig;
+use crate::encryption::DecryptionKey;
+use crate::envelope::{self, Opened};
+use crate::error::{PurityError, Result};
+use crate::estimate::{estimate_write, WriteEstimate, WritePath};
+use crate::fragment;
+use crate::ledger::Ledger;
+use crate::options::WriteOptions;
+use crate::record::PurityRecord;
+use crate::secret::{create_secret_manager, SecretSource};
+use crate::signature::Publisher;
+use crate::storage::{record_write, PurityStorage};
+use crate::utils::{get_address_balance, get_metadata, request_faucet_funds};
+use crate::writer::{WriteReceipt, WriteRequest};
+
+/// Connects to the node of `config` and derives the first address of its secret manager.
+///
+/// Each step runs in its own `tracing` span, see [`crate::timing`].
+#[tracing::instrument(name = "client_setup", skip_all)]
+pub async fn setup_with_client(config: &PurityConfig) -> Result<(SecretManager, Client, Bech32Address)> {
+    let client = Client::builder()
+        .with_node(&config.node_url)?
+        .finish()
+        .instrument(info_span!("client_build"))
+        .await?;
 
-    let secret_manager = SecretManager::try_from_mnemonic(std::env::var("NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC").unwrap())?;
+    let secret_manager = create_secret_manager(SecretSource::from_config(config)?).await?;
 
-    start = Instant::now();
-    let token_supply = client.get_token_supply().await?;
-    duration = start.elapsed().as_millis();
-    println!("Time elapsed in client.get_token_supply() is: {:?}", duration );
-    println!("Token supply: {token_supply}\n\n");
+    let token_supply = client.get_token_supply().instrument(info_span!("token_supply")).await?;
+    log::info!("Token supply: {token_supply}");
 
-    start = Instant::now();
     let addresses = secret_manager
         .generate_ed25519_addresses(GetAddressesOptions::from_client(&client).await?)
+        .instrument(info_span!("addresses"))
         .await?;
     let address = addresses[0];
-    duration = start.elapsed().as_millis();
-    println!("Time elapsed in client.get_addresses() is: {:?}", duration );
+    log::info!("Address: {address}");
 
-    println!("Address: {address}");
     if token_supply < 1000 {
-        start = Instant::now();
-        request_faucet_funds(&client, &address, &env::var("FAUCET_URL").unwrap()).await?;
-        duration = start.elapsed().as_millis();
-        println!("Time elapsed in client.get_addresses() is: {:?}", duration );
+        request_faucet_funds(&client, &address, config.faucet_url()?)
+            .instrument(info_span!("faucet"))
+            .await?;
     }
     Ok((secret_manager, client, address))
 }
 
+/// Writes `metadata` under `tag` in a block signed by `secret_manager`, see [`ClientWriter`].
+///
+/// The receipt holds the state of the block, check it with [`WriteReceipt::confirmed`]. With
+/// `storage` a confirmed write is also recorded in the local store.
+#[allow(clippy::too_many_arguments)]
 pub async fn write_with_client(
+    config: &PurityConfig,
     secret_manager: &mut SecretManager,
-    client: &Client, 
+    ledger: &dyn Ledger, 
     address: Bech32Address,
     tag: &str, 
     metadata: &str,
-    expiration: Option<u32>
-) -> anyhow::Result<BlockId> {
-
-    // metadata = build_payload()
-
-    let mut start;
-    let mut duration;
-
-    let rent_structure = client.get_rent_structure().await?;
-    let token_supply = client.get_token_supply().await?;
-    
-    start = Instant::now();
-
-    let output;
-    match expiration {
-        Some(e) => { 
-            output = BasicOutputBuilder::new_with_minimum_storage_deposit(rent_structure)
-                .add_feature(Feature::Tag(TagFeature::new(tag.as_bytes().to_vec())?))
-                .add_feature(Feature::Metadata(MetadataFeature::new(metadata.as_bytes().to_vec())?))
-                .add_feature(Feature::Sender(SenderFeature::new(address)))
-                .add_unlock_condition(UnlockCondition::Expiration(ExpirationUnlockCondition::new(address, e)?))
-                .add_unlock_condition(UnlockCondition::Address(AddressUnlockCondition::new(address)))
-                .finish_output(token_supply)?;}, 
-        None => { 
-            output = BasicOutputBuilder::new_with_minimum_storage_deposit(rent_structure)
-                .add_feature(Feature::Tag(TagFeature::new(tag.as_bytes().to_vec())?))
-                .add_feature(Feature::Metadata(MetadataFeature::new(metadata.as_bytes().to_vec())?))
-                .add_feature(Feature::Sender(SenderFeature::new(address)))
-                .add_unlock_condition(UnlockCondition::Address(AddressUnlockCondition::new(address)))
-                .finish_output(token_supply)?;
-        }
-    }
+    options: WriteOptions,
+    storage: Option<&PurityStorage>,
+) -> Result<WriteReceipt> {
 
-    duration = start.elapsed().as_millis();
-    println!("Time elapsed with BasicOutputBuilder is: {:?}", duration );
-    
-    let outputs = vec![
-        output
-    ];
-
-    start = Instant::now();
-    let block = client
-        .build_block()
-        .with_secret_manager(&secret_manager)
-        .with_outputs(outputs)?
-        .finish()
-        .await?;
-    duration = start.elapsed().as_millis();
-    println!("Time elapsed in client.block() is: {:?}", duration );
+    // This path always names the sender
+    let request = WriteRequest::new(address, tag, metadata.as_bytes().to_vec(), options.with_sender(true));
+    let receipt = writer::write_request(config, secret_manager, ledger, request).await?;
+    record_write(storage, tag, &receipt, metadata.as_bytes())?;
 
-        
-    // println!("{block:#?}");
-
-    println!("Transaction sent: {}/api/core/v2/blocks/{}", &env::var("NODE_URL").unwrap(), block.id());
-    println!("Block metadata: {}/api/core/v2/blocks/{}/metadata", &env::var("NODE_URL").unwrap(), block.id());
-    println!("Block on Explorer: {}/block/{}\n\n",
-        std::env::var("EXPLORER_URL").unwrap(),
-        block.id()
-    );
+    Ok(receipt)
+}
 
-    println!("Block id: {}", block.id());
-        
+/// Computes the deposit `write_with_client` would lock, and whether the funds of `address` cover it,
+/// without sending anything.
+pub async fn estimate_with_client(
+    client: &Client, 
+    address: Bech32Address,
+    tag: &str, 
+    metadata: &str,
+    options: &WriteOptions
+) -> Result<WriteEstimate> {
 
-    Ok(block.id()) // return outputid da salvare nello storage
+    let estimate = estimate_write(client, WritePath::Client, &address, tag, metadata.as_bytes(), options).await?;
+    let available = get_address_balance(client, &address).await?;
+    Ok(estimate.with_available(available))
 }
 
+/// Returns the ids of every basic output under `tag`, following the indexer pagination.
 pub async fn read_by_tag(
-    client: &Client, 
+    ledger: &dyn Ledger, 
     tag: &str,
-) -> anyhow::Result<Vec<OutputId>> {
-
-    let output_ids = client
-    .basic_output_ids(vec![
-        QueryParameter::Tag(format!("0x{}",hex::encode(tag))),
-
-    ])
-    .await
-    .context("failed to retrieve output ids")?
-    .items;
-    
-    Ok(output_ids)
+) -> Result<Vec<OutputId>> {
+
+    ledger.basic_output_ids(tag, None).await
 }
 
 
 pub async fn read_outputs(
-    client: &Client, 
+    ledger: &dyn Ledger, 
     output_ids: Vec<OutputId>,
-) -> anyhow::Result<Vec<OutputWithMetadata>> {
+) -> Result<Vec<OutputWithMetadata>> {
 
     // Get the outputs by their IDs.
-    let outputs_responses = client
+    let outputs_responses = ledger
         .get_outputs(&output_ids)
-        .await
-        .context("failed to get outputs")?;
+        .await?;
     // println!("Basic outputs: {outputs_responses:#?}");
 
     Ok(outputs_responses)
 }
 
+/// Returns the data written in the output, rebuilding it from its chunks when the output is a manifest.
+///
+/// A signature is checked and stripped, an invalid one is an error; [`read_records`] reports the publisher.
+/// Compressed data is decompressed, encrypted data is returned in its encryption envelope.
+pub async fn read_data(
+    ledger: &dyn Ledger, 
+    output_id: OutputId,
+) -> Result<Vec<u8>> {
+
+    let (tag, data) = read_raw_data(ledger, output_id).await?;
+    match envelope::open(&tag, data)? {
+        Opened::Plain { data, publisher } => verified_payload(data, publisher),
+        Opened::Sealed(envelope) => Ok(envelope),
+    }
+}
+
+/// Like [`read_data`], opening the data with `key` when it is encrypted.
+pub async fn read_data_decrypted(
+    ledger: &dyn Ledger, 
+    output_id: OutputId,
+    key: &dyn DecryptionKey,
+) -> Result<Vec<u8>> {
+
+    let (tag, data) = read_raw_data(ledger, output_id).await?;
+    let (data, publisher) = match envelope::open(&tag, data)? {
+        Opened::Plain { data, publisher } => (data, publisher),
+        Opened::Sealed(sealed) => envelope::unseal(&tag, &sealed, key).await?,
+    };
+    verified_payload(data, publisher)
+}
+
+// Tag and data of the output, reassembled but still in their envelopes
+async fn read_raw_data(ledger: &dyn Ledger, output_id: OutputId) -> Result<(Vec<u8>, Vec<u8>)> {
+    let output = ledger
+        .get_output(&output_id)
+        .await?;
+    let tag = output
+        .output()
+        .features()
+        .and_then(|f| f.tag())
+        .map(|t| t.tag().to_vec())
+        .unwrap_or_default();
+    let metadata = get_metadata(output.output())?;
+    if !fragment::is_manifest(&metadata) {
+        return Ok((tag, metadata));
+    }
+
+    Ok((tag, reassemble(ledger, &metadata).await?))
+}
+
+fn verified_payload(data: Vec<u8>, publisher: Option<Publisher>) -> Result<Vec<u8>> {
+    match publisher {
+        Some(publisher) if !publisher.verified => {
+            Err(PurityError::InvalidData("publisher signature does not match the data".to_string()))
+        }
+        _ => Ok(data),
+    }
+}
+
+/// Reads the data outputs under `tag`, only those owned by `address` if given, decoded as records.
+///
+/// Chunks of fragmented payloads are skipped, manifests are resolved to the original data and
+/// signatures are checked, see [`PurityRecord::publisher`]. A manifest whose payload can't be
+/// rebuilt, with a chunk missing or altered, is skipped with a warning.
+pub async fn read_records(
+    ledger: &dyn Ledger, 
+    tag: &str,
+    address: Option<Bech32Address>,
+) -> Result<Vec<PurityRecord>> {
+
+    let output_ids = match address {
+        Some(address) => read(ledger, tag, address).await?,
+        None => read_by_tag(ledger, tag).await?,
+    };
+    decode_records(ledger, output_ids).await
+}
+
+/// Like [`read_records`], opening with `key` the records sealed for it.
+///
+/// Records sealed for other keys are returned still encrypted, see [`PurityRecord::is_encrypted`].
+pub async fn read_records_decrypted(
+    ledger: &dyn Ledger, 
+    tag: &str,
+    address: Option<Bech32Address>,
+    key: &dyn DecryptionKey,
+) -> Result<Vec<PurityRecord>> {
+
+    let mut records = read_records(ledger, tag, address).await?;
+    for record in records.iter_mut().filter(|r| r.is_encrypted()) {
+        match record.decrypt(key).await {
+            Ok(()) => {}
+            Err(PurityError::InvalidData(e)) => log::debug!("record {} left encrypted: {e}", record.output_id),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+/// Reads the channel under `tag` and rebuilds its order after `from`, following only the messages
+/// signed by `publisher_key`, see [`channel::rebuild`].
+pub async fn read_channel(
+    ledger: &dyn Ledger, 
+    tag: &str,
+    address: Option<Bech32Address>,
+    from: Option<Checkpoint>,
+    publisher_key: &[u8; 32],
+) -> Result<ChannelReport> {
+
+    let records = read_records(ledger, tag, address).await?;
+    let report = channel::rebuild(records, from, publisher_key);
+    for issue in report.issues.iter() {
+        log::warn!("channel {tag}: {issue:?}");
+    }
+    Ok(report)
+}
+
+/// Fetches the outputs and decodes them as records, see [`read_records`].
+pub async fn decode_records(
+    ledger: &dyn Ledger, 
+    output_ids: Vec<OutputId>,
+) -> Result<Vec<PurityRecord>> {
+
+    let hrp = ledger.bech32_hrp().await?;
+    let mut records = Vec::with_capacity(output_ids.len());
+    for output in ledger.get_outputs(&output_ids).await? {
+        records.extend(decode_output(ledger, &output, hrp).await?);
+    }
+    Ok(records)
+}
+
+// Decodes a data output as a record, `None` for a chunk or a manifest whose payload can't be rebuilt
+pub(crate) async fn decode_output(ledger: &dyn Ledger, output: &OutputWithMetadata, hrp: Hrp) -> Result<Option<PurityRecord>> {
+    let mut record = PurityRecord::from_output(output, hrp)?;
+    if fragment::is_chunk(&record.metadata) {
+        return Ok(None);
+    }
+    if fragment::is_manifest(&record.metadata) {
+        match reassemble(ledger, &record.metadata).await {
+            Ok(data) => record.metadata = data,
+            Err(e @ PurityError::NodeUnreachable(_)) => return Err(e),
+            // Anyone can write under a tag, a broken manifest must not hide the other records
+            Err(e) => {
+                log::warn!("record {} skipped, its payload can't be reassembled: {e}", record.output_id);
+                return Ok(None);
+            }
+        }
+    }
+    record.open_payload();
+    Ok(Some(record))
+}
+
+// Rebuilds a fragmented payload from its manifest
+async fn reassemble(ledger: &dyn Ledger, manifest: &[u8]) -> Result<Vec<u8>> {
+    let manifest = fragment::Manifest::from_bytes(manifest)?;
+    // get_outputs keeps the order of the requested ids, which is the payload order
+    let chunks = ledger
+        .get_outputs(&manifest.chunks)
+        .await?
+        .iter()
+        .map(|o| get_metadata(o.output()))
+        .collect::<Result<Vec<_>>>()?;
+
+    manifest.reassemble(&chunks)
+}
+
+/// Returns the ids of every basic output under `tag` owned by `address`, following the indexer pagination.
 pub async fn read(
-    client: &Client, 
+    ledger: &dyn Ledger, 
     tag: &str,
     address: Bech32Address,
-) -> anyhow::Result<Vec<OutputId>> {
-
-    // start = Instant::now();
-    let output_ids = client
-        .basic_output_ids(vec![
-            QueryParameter::Address(address),
-            QueryParameter::Tag(format!("0x{}",hex::encode(tag))),
-            // QueryParameter::HasExpiration(false),git sta
-            // QueryParameter::HasTimelock(false),
-            // QueryParameter::HasStorageDepositReturn(false),
-        ])
-        .await
-        .context("failed to retrieve output ids")?
-        .items;
-    // println!("Address output IDs {output_ids:#?}");
-    
-    // Get the outputs by their IDs.
-    // start = Instant::now();
-    // let outputs_responses = client
-    //     .get_outputs(output_ids)
-    //     .await
-    //     .context("failed to get outputs")?;
-    // duration = start.elapsed().as_millis();
-    // println!("Time elapsed in client.get_outputs() is: {:?}", duration );
-    // println!("Basic outputs: {outputs_responses:#?}");
-    
-    // println!("Block Ids: [");
-    // for o in outputs_responses  {
-    //     println!("is_spent:{}, {}", o.metadata.is_spent, o.metadata.block_id );
-    //     match o.output {
-    //         OutputDto::Basic(b) => { 
-    //             let mut s = String::new();
-    //             for f in b.features {
-    //                 if let FeatureDto::Metadata(m) = f {
-    //                     let bytes = hex::decode(&m.data[2..]).unwrap();
-    //                     // s = String::from_utf8(bytes).unwrap();
-    //                     // println!("{:?}", bytes);
-    //                 }
-    //             }
-    //              println!("{:?}", s);                    
-    //         }
-    //         _ => { println!("No basic");}
-    //     }
-    // }   
-    // println!("]");
-    Ok(output_ids)
+) -> Result<Vec<OutputId>> {
+
+    ledger.basic_output_ids(tag, Some(&address)).await
 }
 
 // ESEMPIO di Output
@@ -258,4 +353,102 @@ pub async fn read(
 //                 ] 
 //         }) 
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use iota_sdk::client::constants::SHIMMER_COIN_TYPE;
+    use iota_sdk::crypto::keys::{bip44::Bip44, x25519::SecretKey};
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+    use iota_sdk::types::block::payload::transaction::TransactionId;
+
+    use super::*;
+    use crate::compression::Compression;
+    use crate::ledger::SimulatedLedger;
+    use crate::writer::{data_output, PurityWriter};
+
+    async fn writer() -> ClientWriter<SimulatedLedger> {
+        let secret_manager = create_secret_manager(SecretSource::HexSeed(hex::encode([5; 32]))).await.unwrap();
+        let config = PurityConfig::builder().with_node_url("simulated").finish().unwrap();
+        ClientWriter::new(SimulatedLedger::new(), secret_manager, config)
+    }
+
+    fn address() -> Bech32Address {
+        Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr")
+    }
+
+    async fn write(writer: &ClientWriter<SimulatedLedger>, payload: Vec<u8>, options: WriteOptions) -> WriteReceipt {
+        writer.write(WriteRequest::new(address(), "t", payload, options)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_are_decoded_whatever_their_layers() {
+        let writer = writer().await;
+        let large: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        write(&writer, b"plain".to_vec(), WriteOptions::default()).await;
+        write(&writer, b"compressed ".repeat(50), WriteOptions::default().with_compression(Compression::Deflate)).await;
+        write(&writer, b"signed".to_vec(), WriteOptions::default().with_signer(Bip44::new(SHIMMER_COIN_TYPE))).await;
+        let fragmented = write(&writer, large.clone(), WriteOptions::default()).await;
+        assert!(fragmented.output_ids.len() > 1);
+
+        // Data written by other tools under the same tag is read as is
+        let ledger = writer.ledger();
+        let (rent_structure, token_supply) = (ledger.rent_structure().await.unwrap(), ledger.token_supply().await.unwrap());
+        let foreign = data_output(&address(), "t", b"PCK1 foreign".to_vec(), &WriteOptions::default(), rent_structure, token_supply);
+        ledger.book(vec![foreign.unwrap()]).unwrap();
+
+        let records = read_records(ledger, "t", None).await.unwrap();
+        let mut payloads: Vec<Vec<u8>> = records.iter().map(|r| r.metadata.clone()).collect();
+        payloads.sort();
+        let mut expected = vec![b"plain".to_vec(), b"compressed ".repeat(50), b"signed".to_vec(), large, b"PCK1 foreign".to_vec()];
+        expected.sort();
+        // Chunks carry no tag and are not records
+        assert_eq!(payloads, expected);
+
+        let signed = records.iter().find(|r| r.metadata == b"signed").unwrap();
+        assert!(signed.publisher.is_some_and(|p| p.verified));
+        assert!(records.iter().filter(|r| r.metadata != b"signed").all(|r| r.publisher.is_none()));
+        assert_eq!(read_data(ledger, fragmented.output_id()).await.unwrap().len(), 20_000);
+    }
+
+    #[tokio::test]
+    async fn manifest_with_a_missing_chunk_is_skipped() {
+        let writer = writer().await;
+        write(&writer, b"plain".to_vec(), WriteOptions::default()).await;
+
+        let ledger = writer.ledger();
+        let (rent_structure, token_supply) = (ledger.rent_structure().await.unwrap(), ledger.token_supply().await.unwrap());
+        let missing = OutputId::new(TransactionId::new([8; 32]), 0).unwrap();
+        let manifest = fragment::Manifest::new(b"payload", vec![missing]).to_bytes();
+        let broken = data_output(&address(), "t", manifest, &WriteOptions::default(), rent_structure, token_supply);
+        ledger.book(vec![broken.unwrap()]).unwrap();
+
+        let records = read_records(ledger, "t", None).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].metadata, b"plain");
+    }
+
+    #[tokio::test]
+    async fn sealed_records_are_opened_with_their_key() {
+        let writer = writer().await;
+        let key = SecretKey::generate().unwrap();
+        let options = WriteOptions::default()
+            .with_recipient(key.public_key())
+            .with_signer(Bip44::new(SHIMMER_COIN_TYPE))
+            .with_compression(Compression::Deflate);
+        let receipt = write(&writer, b"secret ".repeat(20), options).await;
+        let ledger = writer.ledger();
+
+        let records = read_records(ledger, "t", None).await.unwrap();
+        assert!(records[0].is_encrypted() && records[0].publisher.is_none());
+
+        let other = SecretKey::generate().unwrap();
+        let records = read_records_decrypted(ledger, "t", None, &other).await.unwrap();
+        assert!(records[0].is_encrypted());
+
+        let records = read_records_decrypted(ledger, "t", None, &key).await.unwrap();
+        assert!(!records[0].is_encrypted());
+        assert_eq!(records[0].metadata, b"secret ".repeat(20));
+        assert!(records[0].publisher.is_some_and(|p| p.verified));
+        assert_eq!(read_data_decrypted(ledger, receipt.output_id(), &key).await.unwrap(), b"secret ".repeat(20));
+    }
+}
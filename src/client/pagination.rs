@@ -0,0 +1,166 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Paginated queries of the indexer for the data outputs under a tag.
+//!
+//! The indexer answers with pages of output ids and a cursor to the next page. The cursor of
+//! every [`OutputIdsResponse`] can be stored and passed back in [`PageOptions`] to resume.
+
+use futures::stream::{self, Stream};
+use iota_sdk::{
+    client::{
+        node_api::indexer::query_parameters::QueryParameter,
+        Client,
+    },
+    types::{api::plugins::indexer::OutputIdsResponse, block::{address::Bech32Address, output::OutputId}},
+};
+
+use crate::error::Result;
+
+/// Where a paginated query starts and how many ids it gets per page.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PageOptions {
+    /// Ids per page, the node default when `None`.
+    pub page_size: Option<usize>,
+    /// Cursor returned with a previous page, the query starts from the first page when `None`.
+    pub cursor: Option<String>,
+}
+
+impl PageOptions {
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    pub fn with_cursor(mut self, cursor: impl Into<String>) -> Self {
+        self.cursor = Some(cursor.into());
+        self
+    }
+}
+
+/// Fetches a single page of the basic outputs under `tag`, only those owned by `address` if given.
+pub async fn read_page(
+    client: &Client,
+    tag: &str,
+    address: Option<&Bech32Address>,
+    page: &PageOptions,
+) -> Result<OutputIdsResponse> {
+
+    // With a cursor, even an empty one for the first page, basic_output_ids returns a single page
+    let cursor = page.cursor.clone().unwrap_or_default();
+    let mut parameters = query_parameters(tag, address, page);
+    parameters.push(QueryParameter::Cursor(cursor));
+    Ok(client.basic_output_ids(parameters).await?)
+}
+
+/// Lazily walks the pages of the basic outputs under `tag`, starting from `page.cursor`.
+///
+/// Each item carries the cursor of the next page, which can be saved to resume later.
+pub fn read_pages<'a>(
+    client: &'a Client,
+    tag: &'a str,
+    address: Option<&'a Bech32Address>,
+    page: PageOptions,
+) -> impl Stream<Item = Result<OutputIdsResponse>> + Send + 'a {
+
+    stream::try_unfold(Some(page), move |page| async move {
+        let Some(page) = page else {
+            return Ok(None);
+        };
+        let response = read_page(client, tag, address, &page).await?;
+        let next = next_page(&response, page);
+        Ok(Some((response, next)))
+    })
+}
+
+/// Collects the ids of every page, following the cursors until the last one.
+pub async fn read_all_pages(
+    client: &Client,
+    tag: &str,
+    address: Option<&Bech32Address>,
+    page: PageOptions,
+) -> Result<Vec<OutputId>> {
+
+    if page.cursor.is_none() {
+        // Without a cursor basic_output_ids follows the pages by itself
+        return Ok(client.basic_output_ids(query_parameters(tag, address, &page)).await?.items);
+    }
+
+    let mut output_ids = Vec::new();
+    let mut page = Some(page);
+    while let Some(current) = page {
+        let response = read_page(client, tag, address, &current).await?;
+        page = next_page(&response, current);
+        output_ids.extend(response.items);
+    }
+    Ok(output_ids)
+}
+
+// Options of the page after `response`, `None` after the last one
+fn next_page(response: &OutputIdsResponse, page: PageOptions) -> Option<PageOptions> {
+    response.cursor.clone().map(|cursor| PageOptions {
+        cursor: Some(cursor),
+        ..page
+    })
+}
+
+// Filters of the query, without the cursor
+fn query_parameters(tag: &str, address: Option<&Bech32Address>, page: &PageOptions) -> Vec<QueryParameter> {
+    let mut parameters = vec![QueryParameter::Tag(format!("0x{}", hex::encode(tag)))];
+    if let Some(address) = address {
+        parameters.push(QueryParameter::Address(*address));
+    }
+    if let Some(page_size) = page.page_size {
+        parameters.push(QueryParameter::PageSize(page_size));
+    }
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+
+    use super::*;
+
+    fn response(cursor: Option<&str>) -> OutputIdsResponse {
+        OutputIdsResponse {
+            ledger_index: 1,
+            cursor: cursor.map(str::to_string),
+            items: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn query_filters_the_tag_address_and_page_size() {
+        let address = Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr");
+        let page = PageOptions::default().with_page_size(10).with_cursor("ignored");
+        assert_eq!(
+            query_parameters("purity", Some(&address), &page),
+            [
+                QueryParameter::Tag("0x707572697479".to_string()),
+                QueryParameter::Address(address),
+                QueryParameter::PageSize(10),
+            ]
+        );
+        assert_eq!(query_parameters("purity", None, &PageOptions::default()).len(), 1);
+    }
+
+    #[test]
+    fn cursor_leads_to_the_next_page_until_the_last() {
+        let page = PageOptions::default().with_page_size(10);
+        let next = next_page(&response(Some("0x01.10")), page).unwrap();
+        assert_eq!(next, PageOptions::default().with_page_size(10).with_cursor("0x01.10"));
+        assert_eq!(next_page(&response(None), next), None);
+    }
+}
@@ -0,0 +1,641 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streams of the records written under a tag, as they show up on the ledger.
+//!
+//! A [`RecordStream`] is fed by an [`EventSource`] when one is available (the node MQTT broker,
+//! or a channel in tests) and falls back to polling the indexer, backing off while nothing new
+//! is found. Each query lists the output ids first and only downloads the outputs not seen
+//! yet. Records are yielded once, in the order they are discovered.
+
+use std::collections::{HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::{self, Stream};
+use iota_sdk::{
+    client::Client,
+    types::block::{address::Bech32Address, output::OutputId},
+};
+use tokio::sync::{mpsc, Notify};
+
+use crate::error::Result;
+use crate::ledger::Ledger;
+use crate::record::PurityRecord;
+
+use super::decode_records;
+
+/// What a [`RecordStream`] listens to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Subscription {
+    /// Every data output under the tag.
+    Tag(String),
+    /// Data outputs under the tag owned by the address.
+    TagAndAddress(String, Bech32Address),
+}
+
+impl Subscription {
+    pub fn tag(&self) -> &str {
+        match self {
+            Self::Tag(tag) | Self::TagAndAddress(tag, _) => tag,
+        }
+    }
+
+    pub fn address(&self) -> Option<&Bech32Address> {
+        match self {
+            Self::Tag(_) => None,
+            Self::TagAndAddress(_, address) => Some(address),
+        }
+    }
+
+    /// Whether a record pushed by an event source belongs to this subscription.
+    ///
+    /// With an address the record must also be unlocked by it.
+    pub fn matches(&self, record: &PurityRecord) -> bool {
+        let owned = self.address().is_none_or(|address| {
+            record.unlock_conditions.address().is_some_and(|a| a.address() == address.inner())
+        });
+        record.tag == self.tag().as_bytes() && owned
+    }
+}
+
+/// Polling settings of a [`RecordStream`].
+#[derive(Clone, Debug)]
+pub struct SubscriptionOptions {
+    /// Interval between polls right after new records were found.
+    pub min_poll_interval: Duration,
+    /// Longest interval between polls, reached by doubling while nothing new is found.
+    /// With an event source this is the only interval, polls are just a safety net.
+    pub max_poll_interval: Duration,
+    /// Whether the records already on the ledger are yielded when the stream starts.
+    pub include_existing: bool,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            min_poll_interval: Duration::from_secs(1),
+            max_poll_interval: Duration::from_secs(30),
+            include_existing: true,
+        }
+    }
+}
+
+/// Notification coming from an [`EventSource`].
+#[derive(Clone, Debug)]
+pub enum SourceEvent {
+    /// Records pushed by the source, already decoded.
+    Records(Vec<PurityRecord>),
+    /// The ledger changed, the stream should query the indexer again.
+    Changed,
+}
+
+/// Push notifications feeding a [`RecordStream`].
+#[async_trait::async_trait]
+pub trait EventSource: Send + 'static {
+    /// Waits for the next event, `None` once the source is closed and the stream falls back to polling.
+    async fn next_event(&mut self) -> Option<Result<SourceEvent>>;
+}
+
+/// In-process source: events are pushed through the paired sender, e.g. to stand in for the node in tests.
+#[async_trait::async_trait]
+impl EventSource for mpsc::UnboundedReceiver<SourceEvent> {
+    async fn next_event(&mut self) -> Option<Result<SourceEvent>> {
+        self.recv().await.map(Ok)
+    }
+}
+
+/// Records on the ledger feeding a [`RecordStream`], listed by id first so that only the
+/// outputs not seen yet are downloaded.
+#[async_trait::async_trait]
+pub trait RecordQuery: Send + 'static {
+    /// Ids of the outputs currently under the subscription.
+    async fn output_ids(&mut self) -> Result<Vec<OutputId>>;
+
+    /// Downloads and decodes `output_ids`, see [`decode_records`].
+    async fn records(&mut self, output_ids: Vec<OutputId>) -> Result<Vec<PurityRecord>>;
+}
+
+/// [`RecordQuery`] over the indexer of a [`Ledger`].
+pub struct LedgerQuery<L> {
+    ledger: L,
+    subscription: Subscription,
+}
+
+impl<L: Ledger + 'static> LedgerQuery<L> {
+    pub fn new(ledger: L, subscription: Subscription) -> Self {
+        Self { ledger, subscription }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: Ledger + 'static> RecordQuery for LedgerQuery<L> {
+    async fn output_ids(&mut self) -> Result<Vec<OutputId>> {
+        self.ledger.basic_output_ids(self.subscription.tag(), self.subscription.address()).await
+    }
+
+    async fn records(&mut self, output_ids: Vec<OutputId>) -> Result<Vec<PurityRecord>> {
+        decode_records(&self.ledger, output_ids).await
+    }
+}
+
+/// Stops a [`RecordStream`], from any task.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle {
+    inner: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelHandle {
+    /// Ends the stream: it yields `None` as soon as it is polled again.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    async fn cancelled(&self) {
+        loop {
+            // Registered before the check, so a cancel in between is not lost
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Stream of the new records of a [`Subscription`], see [`subscribe`].
+pub struct RecordStream {
+    inner: Pin<Box<dyn Stream<Item = Result<PurityRecord>> + Send>>,
+    cancel: CancelHandle,
+}
+
+impl RecordStream {
+    /// Builds a stream over any record query and event source.
+    ///
+    /// `query` lists the outputs on the ledger on start, after every [`SourceEvent::Changed`] and
+    /// on each poll; only the outputs not seen yet are downloaded.
+    pub fn new<Q, E>(subscription: Subscription, options: SubscriptionOptions, query: Q, events: Option<E>) -> Self
+    where
+        Q: RecordQuery,
+        E: EventSource,
+    {
+        let cancel = CancelHandle::default();
+        let interval = if events.is_some() {
+            options.max_poll_interval
+        } else {
+            options.min_poll_interval
+        };
+        let state = StreamState {
+            subscription,
+            options,
+            query,
+            events,
+            cancel: cancel.clone(),
+            seen: HashSet::new(),
+            pending: VecDeque::new(),
+            interval,
+            retry_delay: None,
+            started: false,
+        };
+
+        Self {
+            inner: Box::pin(stream::unfold(state, StreamState::next)),
+            cancel,
+        }
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+}
+
+impl Stream for RecordStream {
+    type Item = Result<PurityRecord>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+struct StreamState<Q, E> {
+    subscription: Subscription,
+    options: SubscriptionOptions,
+    query: Q,
+    events: Option<E>,
+    cancel: CancelHandle,
+    // Grows with the outputs seen, one OutputId each
+    seen: HashSet<OutputId>,
+    pending: VecDeque<PurityRecord>,
+    interval: Duration,
+    // Wait before the next query after a failed one, doubling up to the longest poll interval
+    retry_delay: Option<Duration>,
+    started: bool,
+}
+
+impl<Q: RecordQuery, E: EventSource> StreamState<Q, E> {
+    async fn next(mut self) -> Option<(Result<PurityRecord>, Self)> {
+        loop {
+            if self.cancel.is_cancelled() {
+                return None;
+            }
+            if let Some(record) = self.pending.pop_front() {
+                return Some((Ok(record), self));
+            }
+
+            let event = if !self.started && self.retry_delay.is_none() {
+                None
+            } else {
+                let wait = self.retry_delay.unwrap_or(self.interval);
+                let Self { cancel, events, .. } = &mut self;
+                tokio::select! {
+                    _ = cancel.cancelled() => return None,
+                    event = next_event(events) => event,
+                    _ = tokio::time::sleep(wait) => None,
+                }
+            };
+
+            match event {
+                Some(Ok(SourceEvent::Records(records))) => {
+                    let records = records.into_iter().filter(|r| self.subscription.matches(r)).collect();
+                    self.accept(records);
+                    continue;
+                }
+                Some(Err(e)) => return Some((Err(e), self)),
+                Some(Ok(SourceEvent::Changed)) | None => {}
+            }
+
+            let found = match self.fetch_new().await {
+                Ok(found) => found,
+                Err(e) => {
+                    let delay = self.retry_delay.map_or(self.options.min_poll_interval, |d| d * 2);
+                    self.retry_delay = Some(delay.min(self.options.max_poll_interval));
+                    return Some((Err(e), self));
+                }
+            };
+            self.retry_delay = None;
+            self.started = true;
+            if self.events.is_none() {
+                self.interval = if found > 0 {
+                    self.options.min_poll_interval
+                } else {
+                    (self.interval * 2).min(self.options.max_poll_interval)
+                };
+            }
+        }
+    }
+
+    // Queues the records of the outputs listed by the query and not seen yet, returns how many they are
+    //
+    // On start without `include_existing` the outputs already there are only marked as seen
+    async fn fetch_new(&mut self) -> Result<usize> {
+        let mut output_ids = self.query.output_ids().await?;
+        output_ids.retain(|id| !self.seen.contains(id));
+        if !self.started && !self.options.include_existing {
+            self.seen.extend(output_ids);
+            return Ok(0);
+        }
+        if output_ids.is_empty() {
+            return Ok(0);
+        }
+        let records = self.query.records(output_ids.clone()).await?;
+        let found = self.accept(records);
+        // Outputs without a record, like broken manifests, are not downloaded again
+        self.seen.extend(output_ids);
+        Ok(found)
+    }
+
+    // Queues the records not seen yet and returns how many they are
+    fn accept(&mut self, records: Vec<PurityRecord>) -> usize {
+        let before = self.pending.len();
+        for record in records {
+            if self.seen.insert(record.output_id) {
+                self.pending.push_back(record);
+            }
+        }
+        self.pending.len() - before
+    }
+}
+
+// Waits for the next event, forever when there is no source; a closed source is dropped
+async fn next_event<E: EventSource>(events: &mut Option<E>) -> Option<Result<SourceEvent>> {
+    match events {
+        Some(source) => {
+            let event = source.next_event().await;
+            if event.is_none() {
+                log::warn!("event source closed, falling back to polling");
+                *events = None;
+            }
+            event
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Subscribes to the records of `subscription`.
+///
+/// Events come from the node MQTT broker when the `mqtt` feature is enabled and the broker is
+/// reachable, otherwise the indexer is polled. Drop or cancel the stream to stop it.
+pub async fn subscribe(client: &Client, subscription: Subscription, options: SubscriptionOptions) -> RecordStream {
+    let query = LedgerQuery::new(client.clone(), subscription.clone());
+
+    #[cfg(feature = "mqtt")]
+    let events = match mqtt::MqttEventSource::new(client, &subscription).await {
+        Ok(source) => Some(source),
+        Err(e) => {
+            log::warn!("MQTT unavailable, polling the indexer: {e}");
+            None
+        }
+    };
+    #[cfg(not(feature = "mqtt"))]
+    let events: Option<mpsc::UnboundedReceiver<SourceEvent>> = None;
+
+    RecordStream::new(subscription, options, query, events)
+}
+
+#[cfg(feature = "mqtt")]
+pub use mqtt::MqttEventSource;
+
+#[cfg(feature = "mqtt")]
+mod mqtt {
+    use iota_sdk::{
+        client::{
+            mqtt::{MqttPayload, Topic, TopicEvent},
+            Client,
+        },
+        types::{
+            api::core::response::OutputWithMetadataResponse,
+            block::{
+                address::Hrp,
+                output::{Output, OutputWithMetadata},
+            },
+            TryFromDto,
+        },
+    };
+    use tokio::sync::mpsc;
+
+    use super::{EventSource, SourceEvent, Subscription};
+    use crate::client::decode_output;
+    use crate::error::{PurityError, Result};
+
+    /// Events from the node MQTT broker.
+    ///
+    /// With an address, the outputs unlockable by it are pushed as records. Outputs can't be
+    /// filtered by tag on the broker, so a tag alone is followed through the latest milestones,
+    /// each one turned into a [`SourceEvent::Changed`].
+    pub struct MqttEventSource {
+        client: Client,
+        topics: Vec<Topic>,
+        receiver: mpsc::UnboundedReceiver<TopicEvent>,
+        hrp: Hrp,
+        token_supply: u64,
+    }
+
+    impl MqttEventSource {
+        pub async fn new(client: &Client, subscription: &Subscription) -> Result<Self> {
+            let topic = match subscription.address() {
+                Some(address) => format!("outputs/unlock/address/{address}"),
+                None => "milestone-info/latest".to_string(),
+            };
+            let topics = vec![Topic::new(topic).map_err(iota_sdk::client::Error::from)?];
+            let hrp = client.get_bech32_hrp().await?;
+            let token_supply = client.get_token_supply().await?;
+
+            let (sender, receiver) = mpsc::unbounded_channel();
+            client
+                .subscribe(topics.clone(), move |event| {
+                    // The receiver is gone once the source is dropped
+                    let _ = sender.send(event.clone());
+                })
+                .await
+                .map_err(iota_sdk::client::Error::from)?;
+
+            Ok(Self {
+                client: client.clone(),
+                topics,
+                receiver,
+                hrp,
+                token_supply,
+            })
+        }
+
+        async fn decode(&self, event: TopicEvent) -> Result<SourceEvent> {
+            if !event.topic.starts_with("outputs/") {
+                return Ok(SourceEvent::Changed);
+            }
+            let MqttPayload::Json(value) = event.payload else {
+                return Err(PurityError::InvalidData(format!("unexpected payload on {}", event.topic)));
+            };
+            let response: OutputWithMetadataResponse =
+                serde_json::from_value(value).map_err(|e| PurityError::InvalidData(e.to_string()))?;
+            let output = Output::try_from_dto_with_params(response.output, self.token_supply)?;
+            let output = OutputWithMetadata::new(output, response.metadata);
+            if !output.output().is_basic() {
+                return Ok(SourceEvent::Records(Vec::new()));
+            }
+
+            let record = decode_output(&self.client, &output, self.hrp).await?;
+            Ok(SourceEvent::Records(record.into_iter().collect()))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl EventSource for MqttEventSource {
+        async fn next_event(&mut self) -> Option<Result<SourceEvent>> {
+            let event = self.receiver.recv().await?;
+            Some(self.decode(event).await)
+        }
+    }
+
+    impl Drop for MqttEventSource {
+        fn drop(&mut self) {
+            let client = self.client.clone();
+            let topics = std::mem::take(&mut self.topics);
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                handle.spawn(async move {
+                    if let Err(e) = client.unsubscribe(topics).await {
+                        log::warn!("MQTT unsubscribe failed: {e}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use futures::StreamExt;
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+
+    use super::*;
+    use crate::error::PurityError;
+    use crate::ledger::SimulatedLedger;
+    use crate::options::WriteOptions;
+    use crate::writer::data_output;
+
+    // Query over a shared ledger, recording the outputs it downloads and failing its first `failures` listings
+    #[derive(Clone, Default)]
+    struct Query {
+        ledger: Arc<SimulatedLedger>,
+        downloaded: Arc<Mutex<Vec<OutputId>>>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl RecordQuery for Query {
+        async fn output_ids(&mut self) -> Result<Vec<OutputId>> {
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(PurityError::NodeUnreachable(None));
+            }
+            self.ledger.basic_output_ids("t", None).await
+        }
+
+        async fn records(&mut self, output_ids: Vec<OutputId>) -> Result<Vec<PurityRecord>> {
+            self.downloaded.lock().unwrap().extend(output_ids.iter().copied());
+            decode_records(self.ledger.as_ref(), output_ids).await
+        }
+    }
+
+    fn polling(options: SubscriptionOptions, query: Query) -> RecordStream {
+        RecordStream::new(Subscription::Tag("t".to_string()), options, query, None::<mpsc::UnboundedReceiver<SourceEvent>>)
+    }
+
+    fn fast_polls() -> SubscriptionOptions {
+        SubscriptionOptions {
+            min_poll_interval: Duration::from_millis(20),
+            max_poll_interval: Duration::from_millis(80),
+            ..Default::default()
+        }
+    }
+
+    fn address(byte: u8) -> Bech32Address {
+        Address::Ed25519(Ed25519Address::new([byte; 32])).to_bech32_unchecked("smr")
+    }
+
+    async fn records(ledger: &SimulatedLedger, writes: &[(&str, Bech32Address)]) -> Vec<PurityRecord> {
+        let rent_structure = ledger.rent_structure().await.unwrap();
+        let token_supply = ledger.token_supply().await.unwrap();
+        let outputs = writes
+            .iter()
+            .map(|(tag, address)| {
+                data_output(address, tag, b"data".to_vec(), &WriteOptions::default(), rent_structure, token_supply).unwrap()
+            })
+            .collect();
+        let sent = ledger.book(outputs).unwrap();
+        let output_ids = (0..writes.len() as u16).map(|i| OutputId::new(sent.transaction_id, i).unwrap()).collect();
+        decode_records(ledger, output_ids).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn pushed_records_are_filtered_by_tag_and_address() {
+        let ledger = SimulatedLedger::new();
+        let (owner, other) = (address(1), address(2));
+        let pushed = records(&ledger, &[("t", owner), ("t", other), ("u", owner)]).await;
+        let later = records(&ledger, &[("t", owner)]).await;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let options = SubscriptionOptions {
+            max_poll_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        // The query sees an empty ledger, records only come from the source
+        let query = Query::default();
+        let mut stream = RecordStream::new(Subscription::TagAndAddress("t".to_string(), owner), options, query, Some(receiver));
+
+        sender.send(SourceEvent::Records(pushed.clone())).unwrap();
+        sender.send(SourceEvent::Records(later.clone())).unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), pushed[0]);
+        // The records of the other address and tag are skipped
+        assert_eq!(stream.next().await.unwrap().unwrap(), later[0]);
+    }
+
+    #[tokio::test]
+    async fn tag_subscription_ignores_the_address() {
+        let ledger = SimulatedLedger::new();
+        let pushed = records(&ledger, &[("t", address(1)), ("t", address(2)), ("u", address(1))]).await;
+
+        let subscription = Subscription::Tag("t".to_string());
+        let matching: Vec<bool> = pushed.iter().map(|r| subscription.matches(r)).collect();
+        assert_eq!(matching, [true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn polls_download_only_new_outputs() {
+        let query = Query::default();
+        let existing = records(&query.ledger, &[("t", address(1)), ("t", address(2))]).await;
+        let mut stream = polling(fast_polls(), query.clone());
+        assert_eq!(stream.next().await.unwrap().unwrap(), existing[0]);
+        assert_eq!(stream.next().await.unwrap().unwrap(), existing[1]);
+
+        let later = records(&query.ledger, &[("t", address(1)), ("u", address(1))]).await;
+        assert_eq!(stream.next().await.unwrap().unwrap(), later[0]);
+        // Polls went on listing the outputs, each one was downloaded once
+        let downloaded = query.downloaded.lock().unwrap().clone();
+        assert_eq!(downloaded, [existing[0].output_id, existing[1].output_id, later[0].output_id]);
+    }
+
+    #[tokio::test]
+    async fn existing_outputs_are_skipped_without_download() {
+        let query = Query::default();
+        records(&query.ledger, &[("t", address(1))]).await;
+        let options = SubscriptionOptions {
+            include_existing: false,
+            ..fast_polls()
+        };
+        let mut stream = polling(options, query.clone());
+        // Starts the stream, nothing new shows up
+        assert!(tokio::time::timeout(Duration::from_millis(50), stream.next()).await.is_err());
+
+        let later = records(&query.ledger, &[("t", address(1))]).await;
+        assert_eq!(stream.next().await.unwrap().unwrap(), later[0]);
+        assert_eq!(*query.downloaded.lock().unwrap(), [later[0].output_id]);
+    }
+
+    #[tokio::test]
+    async fn failed_start_is_retried_with_backoff() {
+        let query = Query::default();
+        query.failures.store(3, Ordering::SeqCst);
+        let existing = records(&query.ledger, &[("t", address(1))]).await;
+        let mut stream = polling(fast_polls(), query);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(stream.next().await.unwrap().is_err());
+        }
+        // No wait before the first query, then 20 and 40 ms
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(stream.next().await.unwrap().unwrap(), existing[0]);
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! [`PurityWriter`] signing with a bare secret manager, without wallet state.
+
+use async_trait::async_trait;
+use iota_sdk::client::secret::SecretManager;
+use iota_sdk::types::block::{address::Bech32Address, output::Output};
+
+use crate::account::{write_batch, BatchEntry, BatchSender};
+use crate::config::PurityConfig;
+use crate::error::{PurityError, Result};
+use crate::fragment;
+use crate::ledger::{Ledger, SentBlock};
+use crate::options::WriteOptions;
+use crate::timing::{Phase, WriteTimings};
+use crate::writer::{chunk_output, data_output, output_id_of, output_ids_of, PurityWriter, WriteReceipt, WriteRequest};
+
+/// Writes through a [`Ledger`], the inputs are picked from the node at every write.
+pub struct ClientWriter<L: Ledger> {
+    ledger: L,
+    secret_manager: SecretManager,
+    config: PurityConfig,
+}
+
+impl<L: Ledger> ClientWriter<L> {
+    pub fn new(ledger: L, secret_manager: SecretManager, config: PurityConfig) -> Self {
+        Self {
+            ledger,
+            secret_manager,
+            config,
+        }
+    }
+
+    pub fn ledger(&self) -> &L {
+        &self.ledger
+    }
+
+    /// Writes many records, packed in as few transactions as the protocol limits allow.
+    ///
+    /// See `PurityAccountExt::write_data_batch`, a failure after some entries were written is a
+    /// `PurityError::PartialBatch` holding their receipts.
+    pub async fn write_batch(&self, address: &Bech32Address, entries: Vec<BatchEntry>) -> Result<Vec<WriteReceipt>> {
+        write_batch(self, &self.config, address, entries).await
+    }
+}
+
+#[async_trait]
+impl<L: Ledger> PurityWriter for ClientWriter<L> {
+    async fn write(&self, request: WriteRequest) -> Result<WriteReceipt> {
+        write_request(&self.config, &self.secret_manager, &self.ledger, request).await
+    }
+}
+
+#[async_trait]
+impl<L: Ledger> BatchSender for ClientWriter<L> {
+    fn ledger(&self) -> &dyn Ledger {
+        &self.ledger
+    }
+
+    async fn encode(&self, options: &WriteOptions, tag: &str, payload: Vec<u8>) -> Result<Vec<u8>> {
+        options.encode(&self.secret_manager, tag.as_bytes(), payload).await
+    }
+
+    async fn send(&self, outputs: Vec<Output>) -> Result<SentBlock> {
+        self.ledger.send_outputs(&self.secret_manager, outputs).await
+    }
+
+    // The inputs are picked from the node at every send, there is no wallet state to sync
+    async fn refresh(&self, _timings: &mut WriteTimings) -> Result<()> {
+        Ok(())
+    }
+
+    async fn write_encoded(
+        &self,
+        config: &PurityConfig,
+        address: &Bech32Address,
+        tag: &str,
+        metadata: Vec<u8>,
+        payload_len: usize,
+        options: WriteOptions,
+    ) -> Result<WriteReceipt> {
+        write_encoded(config, &self.secret_manager, &self.ledger, address, tag, metadata, payload_len, options).await
+    }
+}
+
+// Writes the request in blocks signed by `secret_manager`, fragmenting large payloads
+#[tracing::instrument(name = "write", skip_all, fields(path = "client", tag = %request.tag, payload_len = request.payload.len()))]
+pub(super) async fn write_request(
+    config: &PurityConfig,
+    secret_manager: &SecretManager,
+    ledger: &dyn Ledger,
+    request: WriteRequest,
+) -> Result<WriteReceipt> {
+    let WriteRequest { address, tag, payload, options } = request;
+    let payload_len = payload.len();
+    let metadata = options.encode(secret_manager, tag.as_bytes(), payload).await?;
+    write_encoded(config, secret_manager, ledger, &address, &tag, metadata, payload_len, options).await
+}
+
+// Writes `metadata`, already encoded from a payload of `payload_len` bytes, fragmenting it if needed
+#[allow(clippy::too_many_arguments)]
+async fn write_encoded(
+    config: &PurityConfig,
+    secret_manager: &SecretManager,
+    ledger: &dyn Ledger,
+    address: &Bech32Address,
+    tag: &str,
+    metadata: Vec<u8>,
+    payload_len: usize,
+    options: WriteOptions,
+) -> Result<WriteReceipt> {
+    let encoded_len = metadata.len();
+    let mut timings = WriteTimings::default();
+
+    let rent_structure = ledger.rent_structure().await?;
+    let token_supply = ledger.token_supply().await?;
+
+    // Payloads larger than a MetadataFeature are written as chunks, the data output becomes their manifest
+    let mut output_ids = Vec::new();
+    let mut storage_deposit = 0;
+    let metadata = if fragment::needs_fragmentation(&metadata) {
+        let chunks = fragment::split(&metadata);
+        if chunks.len() > fragment::MAX_CHUNKS {
+            log::warn!("metadata of {} B needs {} chunks, at most {} are supported", metadata.len(), chunks.len(), fragment::MAX_CHUNKS);
+            return Err(PurityError::MetadataTooLarge(None));
+        }
+        log::info!("Fragmenting metadata in {} chunks", chunks.len());
+
+        for batch in chunks.chunks(fragment::CHUNKS_PER_TRANSACTION) {
+            let chunk_outputs = timings.measure(Phase::OutputBuild, || {
+                batch
+                    .iter()
+                    .map(|chunk| chunk_output(address, chunk.clone(), &options, rent_structure, token_supply))
+                    .collect::<Result<Vec<_>>>()
+            })?;
+            let sent = ledger.send_outputs(secret_manager, chunk_outputs.clone()).await?;
+            timings += sent.timings;
+            // The remainder of this block funds the next one once confirmed
+            let (block_id, state) = timings
+                .measure_async(Phase::InclusionWait, ledger.confirm(&sent.block_id, options.confirmation.for_chunks()))
+                .await?;
+            if !state.is_confirmed() {
+                return Err(PurityError::Unconfirmed(block_id, state));
+            }
+            output_ids.extend(output_ids_of(sent.transaction_id, &sent.outputs, &chunk_outputs)?);
+            storage_deposit += chunk_outputs.iter().map(Output::amount).sum::<u64>();
+        }
+        fragment::Manifest::new(&metadata, output_ids.clone()).to_bytes()
+    } else {
+        metadata
+    };
+
+    let output = timings.measure(Phase::OutputBuild, || {
+        data_output(address, tag, metadata, &options, rent_structure, token_supply)
+    })?;
+    let sent = ledger.send_outputs(secret_manager, vec![output.clone()]).await?;
+    timings += sent.timings;
+    log::info!("Block sent: {}/api/core/v2/blocks/{}", config.node_url, sent.block_id);
+
+    let (block_id, inclusion) = timings
+        .measure_async(Phase::InclusionWait, ledger.confirm(&sent.block_id, options.confirmation))
+        .await?;
+    if !inclusion.satisfies(&options.confirmation) {
+        log::warn!("Block {block_id} is {inclusion}");
+    }
+    let explorer_url = config.explorer_block_url(block_id);
+    if let Some(url) = &explorer_url {
+        log::info!("Block on Explorer: {url}");
+    }
+    timings.emit();
+
+    output_ids.push(output_id_of(sent.transaction_id, &sent.outputs, &output)?);
+    Ok(WriteReceipt {
+        transaction_id: sent.transaction_id,
+        block_id,
+        output_ids,
+        storage_deposit: storage_deposit + output.amount(),
+        payload_len,
+        encoded_len,
+        inclusion,
+        timings,
+        explorer_url,
+        alias_id: None,
+    })
+}
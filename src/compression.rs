@@ -0,0 +1,142 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opt-in compression of data payloads, to lower the storage deposit.
+//!
+//! A compressed payload is `magic | original length (u32 LE) | deflate stream`. The payload
+//! envelope flags it as compressed, see [`envelope`](crate::envelope), so compressed and plain
+//! payloads can share a tag.
+
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::error::{PurityError, Result};
+
+/// Magic bytes at the start of every compressed payload.
+pub const COMPRESSED_MAGIC: [u8; 4] = *b"PCD1";
+const HEADER_LEN: usize = COMPRESSED_MAGIC.len() + 4;
+/// Largest payload a compressed payload may expand to, bounds the memory used on read.
+pub const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+/// When a payload is compressed before being written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Always deflate the payload.
+    Deflate,
+    /// Deflate the payload only when the result, header included, is smaller.
+    DeflateIfSmaller,
+}
+
+/// Whether `data` is a compressed payload.
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data[..COMPRESSED_MAGIC.len()] == COMPRESSED_MAGIC
+}
+
+/// Compresses `payload` as requested by `mode`, with whether it was compressed.
+pub fn compress(payload: Vec<u8>, mode: Compression) -> Result<(Vec<u8>, bool)> {
+    if mode == Compression::None {
+        return Ok((payload, false));
+    }
+    let original_len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_DECOMPRESSED_LEN)
+        .ok_or(PurityError::MetadataTooLarge(None))?;
+
+    let mut compressed = Vec::with_capacity(HEADER_LEN + payload.len() / 2);
+    compressed.extend_from_slice(&COMPRESSED_MAGIC);
+    compressed.extend_from_slice(&original_len.to_le_bytes());
+    let mut encoder = DeflateEncoder::new(compressed, flate2::Compression::best());
+    encoder.write_all(&payload).map_err(compression_error)?;
+    let compressed = encoder.finish().map_err(compression_error)?;
+
+    if mode == Compression::DeflateIfSmaller && compressed.len() >= payload.len() {
+        log::debug!("payload of {} B left uncompressed, deflate gives {} B", payload.len(), compressed.len());
+        return Ok((payload, false));
+    }
+    Ok((compressed, true))
+}
+
+/// Restores a compressed payload.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if !is_compressed(data) {
+        return Err(PurityError::InvalidData("payload is not compressed".to_string()));
+    }
+    let original_len =
+        u32::from_le_bytes(data[COMPRESSED_MAGIC.len()..HEADER_LEN].try_into().expect("header length checked")) as usize;
+    if original_len > MAX_DECOMPRESSED_LEN {
+        return Err(PurityError::InvalidData(format!("compressed payload claims {original_len} B")));
+    }
+
+    let mut payload = Vec::with_capacity(original_len);
+    // One byte over the expected length is enough to detect a lying header
+    DeflateDecoder::new(&data[HEADER_LEN..])
+        .take(original_len as u64 + 1)
+        .read_to_end(&mut payload)
+        .map_err(|e| PurityError::InvalidData(format!("can't decompress payload: {e}")))?;
+    if payload.len() != original_len {
+        return Err(PurityError::InvalidData(format!(
+            "decompressed payload is {} B, header says {}", payload.len(), original_len
+        )));
+    }
+    Ok(payload)
+}
+
+fn compression_error(error: std::io::Error) -> PurityError {
+    PurityError::InvalidInput(format!("can't compress payload: {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(original_len: u32) -> Vec<u8> {
+        let mut data = COMPRESSED_MAGIC.to_vec();
+        data.extend_from_slice(&original_len.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn compressed_payload_is_restored() {
+        let payload = b"purity ".repeat(200);
+        let (compressed, done) = compress(payload.clone(), Compression::Deflate).unwrap();
+        assert!(done && is_compressed(&compressed) && compressed.len() < payload.len());
+        assert_eq!(decompress(&compressed).unwrap(), payload);
+    }
+
+    #[test]
+    fn incompressible_payload_is_left_as_is() {
+        let payload: Vec<u8> = (0..64u8).collect();
+        assert_eq!(compress(payload.clone(), Compression::DeflateIfSmaller).unwrap(), (payload.clone(), false));
+        assert_eq!(compress(payload.clone(), Compression::None).unwrap(), (payload, false));
+    }
+
+    #[test]
+    fn decompression_is_bounded_by_the_header() {
+        assert!(decompress(b"not compressed").is_err());
+
+        let oversized = header(MAX_DECOMPRESSED_LEN as u32 + 1);
+        assert!(matches!(decompress(&oversized), Err(PurityError::InvalidData(_))));
+
+        // Headers claiming less or more than the deflated data holds
+        let (compressed, _) = compress(vec![0; 1000], Compression::Deflate).unwrap();
+        for original_len in [999, 1001] {
+            let mut lying = header(original_len);
+            lying.extend_from_slice(&compressed[HEADER_LEN..]);
+            assert!(decompress(&lying).is_err());
+        }
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Node, wallet and secret settings, passed explicitly to the setup and write functions.
+//!
+//! A [`PurityConfig`] can be built in code with [`PurityConfig::builder`], loaded from a TOML
+//! file with [`PurityConfig::from_toml_file`] or filled from the environment with
+//! [`PurityConfig::from_env`], using the same variable names as `.env.example`.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::{PurityError, Result};
+use crate::utils::env_var;
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PurityConfig {
+    pub node_url: String,
+    #[serde(default)]
+    pub explorer_url: Option<String>,
+    #[serde(default)]
+    pub faucet_url: Option<String>,
+    #[serde(default)]
+    pub stronghold_password: Option<String>,
+    #[serde(default)]
+    pub stronghold_snapshot_path: Option<PathBuf>,
+    #[serde(default)]
+    pub wallet_db_path: Option<PathBuf>,
+    #[serde(default)]
+    pub mnemonic: Option<String>,
+    #[serde(default)]
+    pub seed: Option<String>,
+    #[serde(default)]
+    pub storage_path: Option<PathBuf>,
+}
+
+impl PurityConfig {
+    pub fn builder() -> PurityConfigBuilder {
+        PurityConfigBuilder::default()
+    }
+
+    /// Reads the configuration from the environment, only `NODE_URL` is required.
+    ///
+    /// The mnemonic is taken from `MNEMONIC`, `NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC` or its `_1`
+    /// variant, the hex seed from `SEED` or `NON_SECURE_USE_OF_DEVELOPMENT_SEED_1`. Setting more
+    /// than one variable for the same secret is an error, a development secret never silently
+    /// stands in for the real one or the other way round.
+    pub fn from_env() -> Result<Self> {
+        let optional = |name: &str| env_var(name).ok();
+        Ok(Self {
+            node_url: env_var("NODE_URL")?,
+            explorer_url: optional("EXPLORER_URL"),
+            faucet_url: optional("FAUCET_URL"),
+            stronghold_password: optional("STRONGHOLD_PASSWORD"),
+            stronghold_snapshot_path: optional("STRONGHOLD_SNAPSHOT_PATH").map(PathBuf::from),
+            wallet_db_path: optional("WALLET_DB_PATH").map(PathBuf::from),
+            mnemonic: exclusive_env_var(&[
+                "MNEMONIC",
+                "NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC",
+                "NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC_1",
+            ])?,
+            seed: exclusive_env_var(&["SEED", "NON_SECURE_USE_OF_DEVELOPMENT_SEED_1"])?,
+            storage_path: optional("STORAGE_PATH").map(PathBuf::from),
+        })
+    }
+
+    pub fn from_toml_str(toml: &str) -> Result<Self> {
+        toml::from_str(toml).map_err(|e| PurityError::InvalidInput(format!("invalid configuration: {e}")))
+    }
+
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path)
+            .map_err(|e| PurityError::InvalidInput(format!("can't read configuration {}: {e}", path.display())))?;
+        Self::from_toml_str(&toml)
+    }
+
+    pub fn faucet_url(&self) -> Result<&str> {
+        self.faucet_url.as_deref().ok_or(PurityError::MissingConfig("faucet_url"))
+    }
+
+    pub fn stronghold_password(&self) -> Result<&str> {
+        self.stronghold_password.as_deref().ok_or(PurityError::MissingConfig("stronghold_password"))
+    }
+
+    pub fn stronghold_snapshot_path(&self) -> Result<&Path> {
+        self.stronghold_snapshot_path.as_deref().ok_or(PurityError::MissingConfig("stronghold_snapshot_path"))
+    }
+
+    pub fn wallet_db_path(&self) -> Result<&Path> {
+        self.wallet_db_path.as_deref().ok_or(PurityError::MissingConfig("wallet_db_path"))
+    }
+
+    pub fn mnemonic(&self) -> Result<&str> {
+        self.mnemonic.as_deref().ok_or(PurityError::MissingConfig("mnemonic"))
+    }
+
+    pub fn seed(&self) -> Result<&str> {
+        self.seed.as_deref().ok_or(PurityError::MissingConfig("seed"))
+    }
+
+    pub fn storage_path(&self) -> Result<&Path> {
+        self.storage_path.as_deref().ok_or(PurityError::MissingConfig("storage_path"))
+    }
+
+    /// Link to a block on the explorer, if an explorer is configured.
+    pub fn explorer_block_url(&self, block_id: impl std::fmt::Display) -> Option<String> {
+        self.explorer_url.as_ref().map(|url| format!("{url}/block/{block_id}"))
+    }
+}
+
+// Value of the only variable of `names` that is set
+fn exclusive_env_var(names: &[&str]) -> Result<Option<String>> {
+    let set: Vec<(&str, String)> = names.iter().filter_map(|name| env_var(name).ok().map(|v| (*name, v))).collect();
+    match set.as_slice() {
+        [] => Ok(None),
+        [(_, value)] => Ok(Some(value.clone())),
+        [(first, _), (second, _), ..] => Err(PurityError::InvalidInput(format!("{first} and {second} are both set, keep only one"))),
+    }
+}
+
+// Secrets are never printed
+impl std::fmt::Debug for PurityConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PurityConfig")
+            .field("node_url", &self.node_url)
+            .field("explorer_url", &self.explorer_url)
+            .field("faucet_url", &self.faucet_url)
+            .field("stronghold_password", &self.stronghold_password.as_ref().map(|_| "<redacted>"))
+            .field("stronghold_snapshot_path", &self.stronghold_snapshot_path)
+            .field("wallet_db_path", &self.wallet_db_path)
+            .field("mnemonic", &self.mnemonic.as_ref().map(|_| "<redacted>"))
+            .field("seed", &self.seed.as_ref().map(|_| "<redacted>"))
+            .field("storage_path", &self.storage_path)
+            .finish()
+    }
+}
+
+#[derive(Default)]
+pub struct PurityConfigBuilder {
+    config: PurityConfig,
+}
+
+impl PurityConfigBuilder {
+    pub fn with_node_url(mut self, node_url: impl Into<String>) -> Self {
+        self.config.node_url = node_url.into();
+        self
+    }
+
+    pub fn with_explorer_url(mut self, explorer_url: impl Into<String>) -> Self {
+        self.config.explorer_url = Some(explorer_url.into());
+        self
+    }
+
+    pub fn with_faucet_url(mut self, faucet_url: impl Into<String>) -> Self {
+        self.config.faucet_url = Some(faucet_url.into());
+        self
+    }
+
+    pub fn with_stronghold_password(mut self, stronghold_password: impl Into<String>) -> Self {
+        self.config.stronghold_password = Some(stronghold_password.into());
+        self
+    }
+
+    pub fn with_stronghold_snapshot_path(mut self, stronghold_snapshot_path: impl Into<PathBuf>) -> Self {
+        self.config.stronghold_snapshot_path = Some(stronghold_snapshot_path.into());
+        self
+    }
+
+    pub fn with_wallet_db_path(mut self, wallet_db_path: impl Into<PathBuf>) -> Self {
+        self.config.wallet_db_path = Some(wallet_db_path.into());
+        self
+    }
+
+    pub fn with_mnemonic(mut self, mnemonic: impl Into<String>) -> Self {
+        self.config.mnemonic = Some(mnemonic.into());
+        self
+    }
+
+    pub fn with_seed(mut self, seed: impl Into<String>) -> Self {
+        self.config.seed = Some(seed.into());
+        self
+    }
+
+    pub fn with_storage_path(mut self, storage_path: impl Into<PathBuf>) -> Self {
+        self.config.storage_path = Some(storage_path.into());
+        self
+    }
+
+    pub fn finish(self) -> Result<PurityConfig> {
+        if self.config.node_url.is_empty() {
+            return Err(PurityError::MissingConfig("node_url"));
+        }
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::SecretSource;
+
+    #[test]
+    fn toml_needs_the_node_url_and_known_fields_only() {
+        let config = PurityConfig::from_toml_str("node_url = \"http://node\"\nstorage_path = \"db\"\n").unwrap();
+        assert_eq!(config.node_url, "http://node");
+        assert_eq!(config.storage_path().unwrap(), Path::new("db"));
+        assert!(matches!(config.seed(), Err(PurityError::MissingConfig("seed"))));
+
+        assert!(PurityConfig::from_toml_str("storage_path = \"db\"\n").is_err());
+        assert!(PurityConfig::from_toml_str("node_url = \"http://node\"\nnode = \"typo\"\n").is_err());
+    }
+
+    #[test]
+    fn builder_needs_the_node_url() {
+        assert!(matches!(PurityConfig::builder().finish(), Err(PurityError::MissingConfig("node_url"))));
+        let config = PurityConfig::builder().with_node_url("http://node").with_explorer_url("http://explorer").finish().unwrap();
+        assert_eq!(config.explorer_block_url("0x01").unwrap(), "http://explorer/block/0x01");
+    }
+
+    // The environment is shared by the whole test binary, it is only touched here
+    #[test]
+    fn environment_secrets_are_exclusive() {
+        let names = ["NODE_URL", "MNEMONIC", "NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC", "SEED"];
+        for name in names {
+            std::env::remove_var(name);
+        }
+        assert!(matches!(PurityConfig::from_env(), Err(PurityError::MissingEnvVar(..))));
+
+        std::env::set_var("NODE_URL", "http://node");
+        std::env::set_var("NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC", "development words");
+        std::env::set_var("SEED", "0x00");
+        let config = PurityConfig::from_env().unwrap();
+        assert_eq!(config.node_url, "http://node");
+        assert_eq!(config.mnemonic().unwrap(), "development words");
+        assert_eq!(config.seed().unwrap(), "0x00");
+        // Both secrets are read, but signing with them is ambiguous
+        assert!(matches!(SecretSource::from_config(&config), Err(PurityError::InvalidInput(_))));
+
+        std::env::set_var("MNEMONIC", "real words");
+        let error = PurityConfig::from_env().unwrap_err();
+        assert!(matches!(error, PurityError::InvalidInput(ref message) if message.contains("MNEMONIC and NON_SECURE_USE_OF_DEVELOPMENT_MNEMONIC")));
+
+        for name in names {
+            std::env::remove_var(name);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How long a write waits for its block, and what the ledger made of it.
+//!
+//! A [`Confirmation`] is set per write in `WriteOptions`; the write returns the
+//! [`InclusionState`] observed when the wait ended, without failing when it falls short, so
+//! callers decide what to do with a pending block. A write is stored only once its state is
+//! [`InclusionState::Confirmed`]: a timed out wait leaves the block pending, and a conflicting
+//! transaction created no output.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Default time to wait for a block.
+pub const DEFAULT_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a write waits for before returning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Confirmation {
+    /// Returns as soon as the block is submitted.
+    None,
+    /// Waits until the block is solid on the node, i.e. attached to the tangle.
+    Inclusion { timeout: Duration },
+    /// Waits until the block is referenced by a milestone and its transaction applied or
+    /// rejected, promoting or reattaching it as needed.
+    Milestone { timeout: Duration },
+}
+
+impl Default for Confirmation {
+    fn default() -> Self {
+        Self::milestone()
+    }
+}
+
+impl Confirmation {
+    /// Waits for inclusion up to [`DEFAULT_CONFIRMATION_TIMEOUT`].
+    pub fn inclusion() -> Self {
+        Self::Inclusion {
+            timeout: DEFAULT_CONFIRMATION_TIMEOUT,
+        }
+    }
+
+    /// Waits for a milestone up to [`DEFAULT_CONFIRMATION_TIMEOUT`].
+    pub fn milestone() -> Self {
+        Self::Milestone {
+            timeout: DEFAULT_CONFIRMATION_TIMEOUT,
+        }
+    }
+
+    /// How long to wait, `None` when not waiting.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            Self::None => None,
+            Self::Inclusion { timeout } | Self::Milestone { timeout } => Some(*timeout),
+        }
+    }
+
+    /// The policy satisfying both `self` and `other`, for writes sharing a transaction.
+    pub(crate) fn strongest(self, other: Self) -> Self {
+        let rank = |c: &Self| match c {
+            Self::None => 0,
+            Self::Inclusion { .. } => 1,
+            Self::Milestone { .. } => 2,
+        };
+        let timeout = self.timeout().max(other.timeout()).unwrap_or(DEFAULT_CONFIRMATION_TIMEOUT);
+        match rank(&self).max(rank(&other)) {
+            0 => Self::None,
+            1 => Self::Inclusion { timeout },
+            _ => Self::Milestone { timeout },
+        }
+    }
+
+    /// The policy of the chunks of a fragmented payload: they are spent by the next
+    /// transaction and referenced by the manifest, so they always wait for a milestone.
+    pub(crate) fn for_chunks(&self) -> Self {
+        Self::Milestone {
+            timeout: self.timeout().unwrap_or(DEFAULT_CONFIRMATION_TIMEOUT),
+        }
+    }
+}
+
+impl fmt::Display for Confirmation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Inclusion { timeout } => write!(f, "inclusion ({timeout:?})"),
+            Self::Milestone { timeout } => write!(f, "milestone ({timeout:?})"),
+        }
+    }
+}
+
+/// State of a block on the ledger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum InclusionState {
+    /// Not known to be attached: not waited for, or the wait timed out.
+    Pending,
+    /// Attached to the tangle, not yet referenced by a milestone.
+    Solid,
+    /// Referenced by a milestone, the transaction is applied and its outputs exist.
+    Confirmed { milestone_index: u32 },
+    /// Referenced by a milestone, the transaction was rejected with the node conflict reason code.
+    Conflicting { milestone_index: u32, reason: u8 },
+}
+
+impl InclusionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Solid => "solid",
+            Self::Confirmed { .. } => "confirmed",
+            Self::Conflicting { .. } => "conflicting",
+        }
+    }
+
+    /// Whether the outputs of the transaction exist on the ledger.
+    pub fn is_confirmed(&self) -> bool {
+        matches!(self, Self::Confirmed { .. })
+    }
+
+    /// Whether the state can't change anymore.
+    pub fn is_final(&self) -> bool {
+        matches!(self, Self::Confirmed { .. } | Self::Conflicting { .. })
+    }
+
+    /// Whether the state is what `confirmation` waits for.
+    pub fn satisfies(&self, confirmation: &Confirmation) -> bool {
+        match confirmation {
+            Confirmation::None => !matches!(self, Self::Conflicting { .. }),
+            Confirmation::Inclusion { .. } => matches!(self, Self::Solid | Self::Confirmed { .. }),
+            Confirmation::Milestone { .. } => self.is_confirmed(),
+        }
+    }
+}
+
+impl fmt::Display for InclusionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Confirmed { milestone_index } => write!(f, "confirmed by milestone {milestone_index}"),
+            Self::Conflicting { milestone_index, reason } => {
+                write!(f, "conflicting in milestone {milestone_index} (reason {reason})")
+            }
+            state => write!(f, "{}", state.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIRMED: InclusionState = InclusionState::Confirmed { milestone_index: 3 };
+    const CONFLICTING: InclusionState = InclusionState::Conflicting { milestone_index: 3, reason: 1 };
+
+    #[test]
+    fn states_satisfy_what_they_reach() {
+        let states = [InclusionState::Pending, InclusionState::Solid, CONFIRMED, CONFLICTING];
+        let satisfied = |confirmation: Confirmation| states.map(|s| s.satisfies(&confirmation));
+        assert_eq!(satisfied(Confirmation::None), [true, true, true, false]);
+        assert_eq!(satisfied(Confirmation::inclusion()), [false, true, true, false]);
+        assert_eq!(satisfied(Confirmation::milestone()), [false, false, true, false]);
+        assert_eq!(states.map(|s| s.is_final()), [false, false, true, true]);
+    }
+
+    #[test]
+    fn shared_transactions_wait_for_the_strongest() {
+        let short = Duration::from_secs(1);
+        let long = Duration::from_secs(9);
+        assert_eq!(Confirmation::None.strongest(Confirmation::None), Confirmation::None);
+        assert_eq!(
+            Confirmation::Inclusion { timeout: long }.strongest(Confirmation::Milestone { timeout: short }),
+            Confirmation::Milestone { timeout: long }
+        );
+        assert_eq!(
+            Confirmation::None.strongest(Confirmation::Inclusion { timeout: short }),
+            Confirmation::Inclusion { timeout: short }
+        );
+        assert_eq!(Confirmation::None.for_chunks(), Confirmation::milestone());
+        assert_eq!(Confirmation::Inclusion { timeout: short }.for_chunks(), Confirmation::Milestone { timeout: short });
+    }
+
+    #[test]
+    fn states_serialize_with_their_name() {
+        assert_eq!(serde_json::to_value(CONFIRMED).unwrap(), serde_json::json!({ "state": "confirmed", "milestone_index": 3 }));
+        assert_eq!(serde_json::to_value(InclusionState::Pending).unwrap(), serde_json::json!({ "state": "pending" }));
+        assert_eq!(CONFLICTING.to_string(), "conflicting in milestone 3 (reason 1)");
+    }
+}
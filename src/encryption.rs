@@ -0,0 +1,300 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Optional end-to-end encryption of data payloads.
+//!
+//! A payload is sealed for the X25519 public key of its reader: a fresh ephemeral key is agreed
+//! with the recipient key, the shared secret goes through HKDF-SHA256 and the result keys
+//! XChaCha20-Poly1305. The envelope is
+//! `magic | ephemeral public key (32) | nonce (24) | tag (16) | ciphertext`.
+//!
+//! The recipient secret key can live in memory or in the Stronghold vault of the wallet, see
+//! [`StrongholdKey`], in which case it never leaves the vault.
+
+use iota_sdk::client::stronghold::{Error as StrongholdError, StrongholdAdapter};
+use iota_sdk::crypto::{
+    ciphers::{chacha::XChaCha20Poly1305, traits::Aead},
+    keys::x25519::{PublicKey, SecretKey, PUBLIC_KEY_LENGTH},
+    macs::hmac::HMAC_SHA256,
+};
+use iota_stronghold::{
+    procedures::{AeadCipher, AeadDecrypt, GenerateKey, Hkdf, KeyType, Sha2Hash, X25519DiffieHellman},
+    Location,
+};
+use zeroize::Zeroizing;
+
+use crate::error::{PurityError, Result};
+
+/// Magic bytes at the start of every encrypted payload.
+pub const ENCRYPTED_MAGIC: [u8; 4] = *b"PEN1";
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Bytes added by the encryption to the payload.
+pub const ENVELOPE_OVERHEAD: usize = ENCRYPTED_MAGIC.len() + PUBLIC_KEY_LENGTH + NONCE_LEN + TAG_LEN;
+// HKDF info, binds the derived key to this scheme
+const KDF_INFO: &[u8] = b"purity-e2e-v1";
+
+// Stronghold client loaded by the wallet adapter, the key is kept next to the wallet seed
+const STRONGHOLD_CLIENT_PATH: &[u8] = b"iota_seed";
+const STRONGHOLD_VAULT_PATH: &[u8] = b"purity-encryption";
+const STRONGHOLD_KEY_RECORD: &[u8] = b"x25519-key";
+const STRONGHOLD_SHARED_RECORD: &[u8] = b"x25519-shared";
+const STRONGHOLD_AEAD_KEY_RECORD: &[u8] = b"aead-key";
+
+/// Whether `data` is an encrypted payload.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.len() >= ENVELOPE_OVERHEAD && data[..ENCRYPTED_MAGIC.len()] == ENCRYPTED_MAGIC
+}
+
+/// Seals `plaintext` for the owner of `recipient`.
+pub fn encrypt(recipient: &PublicKey, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let ephemeral = SecretKey::generate().map_err(crypto_error)?;
+    let ephemeral_public = ephemeral.public_key();
+    let shared = ephemeral.diffie_hellman(recipient);
+    let key = derive_key(shared.as_bytes(), &salt(&ephemeral_public, recipient));
+
+    let nonce = XChaCha20Poly1305::random_nonce().map_err(crypto_error)?;
+    let mut tag = [0u8; TAG_LEN];
+    let mut ciphertext = vec![0u8; plaintext.len()];
+    XChaCha20Poly1305::try_encrypt(key.as_ref(), &nonce, &ENCRYPTED_MAGIC, plaintext, &mut ciphertext, &mut tag)
+        .map_err(crypto_error)?;
+
+    let mut envelope = Vec::with_capacity(ENVELOPE_OVERHEAD + ciphertext.len());
+    envelope.extend_from_slice(&ENCRYPTED_MAGIC);
+    envelope.extend_from_slice(ephemeral_public.as_slice());
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&tag);
+    envelope.extend_from_slice(&ciphertext);
+    Ok(envelope)
+}
+
+/// Holder of an X25519 secret key, able to open the payloads sealed for its public key.
+#[async_trait::async_trait]
+pub trait DecryptionKey: Send + Sync {
+    fn public_key(&self) -> PublicKey;
+
+    async fn decrypt(&self, envelope: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[async_trait::async_trait]
+impl DecryptionKey for SecretKey {
+    fn public_key(&self) -> PublicKey {
+        SecretKey::public_key(self)
+    }
+
+    async fn decrypt(&self, envelope: &[u8]) -> Result<Vec<u8>> {
+        let envelope = Envelope::parse(envelope)?;
+        let shared = self.diffie_hellman(&envelope.ephemeral);
+        let key = derive_key(shared.as_bytes(), &salt(&envelope.ephemeral, &self.public_key()));
+
+        let mut plaintext = vec![0u8; envelope.ciphertext.len()];
+        XChaCha20Poly1305::try_decrypt(
+            key.as_ref(),
+            envelope.nonce,
+            &ENCRYPTED_MAGIC,
+            &mut plaintext,
+            envelope.ciphertext,
+            envelope.tag,
+        )
+        .map_err(|_| PurityError::InvalidData("payload can't be decrypted with this key".to_string()))?;
+        Ok(plaintext)
+    }
+}
+
+/// X25519 key stored in the Stronghold snapshot of the wallet.
+///
+/// Decryption runs inside the vault, only the plaintext comes out. The key is generated on first
+/// use and the snapshot is written right away, so it is never lost.
+pub struct StrongholdKey<'a> {
+    adapter: &'a StrongholdAdapter,
+    public_key: PublicKey,
+}
+
+impl<'a> StrongholdKey<'a> {
+    /// Loads the encryption key of the vault, generating it on first use and then writing the
+    /// snapshot to its file.
+    pub async fn load_or_generate(adapter: &'a StrongholdAdapter) -> Result<StrongholdKey<'a>> {
+        let stronghold = adapter.inner().await;
+        let client = stronghold
+            .get_client(STRONGHOLD_CLIENT_PATH)
+            .or_else(|_| stronghold.create_client(STRONGHOLD_CLIENT_PATH))
+            .map_err(StrongholdError::from)?;
+
+        let key = key_location(STRONGHOLD_KEY_RECORD);
+        let generated = !client.record_exists(&key).map_err(StrongholdError::from)?;
+        if generated {
+            log::info!("generating the Stronghold encryption key");
+            client
+                .execute_procedure(GenerateKey { ty: KeyType::X25519, output: key.clone() })
+                .map_err(StrongholdError::from)?;
+        }
+        let public_key = client
+            .execute_procedure(iota_stronghold::procedures::PublicKey { ty: KeyType::X25519, private_key: key })
+            .map_err(StrongholdError::from)?;
+        // The snapshot writer takes the same lock
+        drop(stronghold);
+        if generated {
+            adapter.write_stronghold_snapshot(None).await?;
+        }
+
+        Ok(Self {
+            adapter,
+            public_key: PublicKey::try_from_slice(&public_key).map_err(crypto_error)?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl DecryptionKey for StrongholdKey<'_> {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    async fn decrypt(&self, envelope: &[u8]) -> Result<Vec<u8>> {
+        let envelope = Envelope::parse(envelope)?;
+        let stronghold = self.adapter.inner().await;
+        let client = stronghold.get_client(STRONGHOLD_CLIENT_PATH).map_err(StrongholdError::from)?;
+
+        let shared = key_location(STRONGHOLD_SHARED_RECORD);
+        let aead_key = key_location(STRONGHOLD_AEAD_KEY_RECORD);
+        client
+            .execute_procedure(X25519DiffieHellman {
+                public_key: envelope.ephemeral.to_bytes(),
+                private_key: key_location(STRONGHOLD_KEY_RECORD),
+                shared_key: shared.clone(),
+            })
+            .map_err(StrongholdError::from)?;
+        client
+            .execute_procedure(Hkdf {
+                hash_type: Sha2Hash::Sha256,
+                salt: salt(&envelope.ephemeral, &self.public_key).to_vec(),
+                label: KDF_INFO.to_vec(),
+                ikm: shared,
+                okm: aead_key.clone(),
+            })
+            .map_err(StrongholdError::from)?;
+        let plaintext = client.execute_procedure(AeadDecrypt {
+            cipher: AeadCipher::XChaCha20Poly1305,
+            associated_data: ENCRYPTED_MAGIC.to_vec(),
+            ciphertext: envelope.ciphertext.to_vec(),
+            tag: envelope.tag.to_vec(),
+            nonce: envelope.nonce.to_vec(),
+            key: aead_key,
+        });
+
+        // The derived secrets are only needed for this payload
+        let vault = client.vault(STRONGHOLD_VAULT_PATH);
+        vault.delete_secret(STRONGHOLD_SHARED_RECORD).map_err(StrongholdError::from)?;
+        vault.delete_secret(STRONGHOLD_AEAD_KEY_RECORD).map_err(StrongholdError::from)?;
+
+        plaintext.map_err(|_| PurityError::InvalidData("payload can't be decrypted with this key".to_string()))
+    }
+}
+
+struct Envelope<'a> {
+    ephemeral: PublicKey,
+    nonce: &'a [u8],
+    tag: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self> {
+        if !is_encrypted(bytes) {
+            return Err(PurityError::InvalidData("payload is not encrypted".to_string()));
+        }
+        let (ephemeral, rest) = bytes[ENCRYPTED_MAGIC.len()..].split_at(PUBLIC_KEY_LENGTH);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+        Ok(Self {
+            ephemeral: PublicKey::try_from_slice(ephemeral).map_err(crypto_error)?,
+            nonce,
+            tag,
+            ciphertext,
+        })
+    }
+}
+
+fn key_location(record: &[u8]) -> Location {
+    Location::generic(STRONGHOLD_VAULT_PATH, record)
+}
+
+fn salt(ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; 2 * PUBLIC_KEY_LENGTH] {
+    let mut salt = [0u8; 2 * PUBLIC_KEY_LENGTH];
+    salt[..PUBLIC_KEY_LENGTH].copy_from_slice(ephemeral.as_slice());
+    salt[PUBLIC_KEY_LENGTH..].copy_from_slice(recipient.as_slice());
+    salt
+}
+
+// HKDF-SHA256 with a single output block, the same derivation as the Stronghold Hkdf procedure
+fn derive_key(shared: &[u8], salt: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut prk = Zeroizing::new([0u8; 32]);
+    HMAC_SHA256(shared, salt, &mut prk);
+    let mut info = KDF_INFO.to_vec();
+    info.push(1);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    HMAC_SHA256(&info, prk.as_ref(), &mut okm);
+    okm
+}
+
+fn crypto_error(error: iota_sdk::crypto::Error) -> PurityError {
+    StrongholdError::from(error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::{open_stronghold, StrongholdInit};
+
+    #[tokio::test]
+    async fn sealed_payload_opens_with_the_recipient_key_only() {
+        let recipient = SecretKey::generate().unwrap();
+        let envelope = encrypt(&recipient.public_key(), b"payload").unwrap();
+        assert!(is_encrypted(&envelope));
+        assert_eq!(envelope.len(), ENVELOPE_OVERHEAD + b"payload".len());
+        assert_eq!(DecryptionKey::decrypt(&recipient, &envelope).await.unwrap(), b"payload");
+
+        let other = SecretKey::generate().unwrap();
+        assert!(DecryptionKey::decrypt(&other, &envelope).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn generated_stronghold_key_is_kept_in_the_snapshot() {
+        // Fast snapshot encryption, as in the iota-sdk tests; never do this outside tests
+        iota_stronghold::engine::snapshot::try_set_encrypt_work_factor(0).unwrap();
+        let path = std::env::temp_dir().join(format!("purity-encryption-{}.stronghold", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let password = "correct horse battery staple".to_string();
+        let envelope = {
+            let stronghold = open_stronghold(&path, password.clone(), StrongholdInit::Create).await.unwrap();
+            let key = StrongholdKey::load_or_generate(&stronghold).await.unwrap();
+            encrypt(&key.public_key(), b"payload").unwrap()
+        };
+
+        // Reopened from the file alone, without any other snapshot write
+        let stronghold = open_stronghold(&path, password, StrongholdInit::Open).await.unwrap();
+        let key = StrongholdKey::load_or_generate(&stronghold).await.unwrap();
+        assert_eq!(key.decrypt(&envelope).await.unwrap(), b"payload");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn altered_envelope_is_rejected() {
+        let recipient = SecretKey::generate().unwrap();
+        let mut envelope = encrypt(&recipient.public_key(), b"payload").unwrap();
+        *envelope.last_mut().unwrap() ^= 1;
+        assert!(DecryptionKey::decrypt(&recipient, &envelope).await.is_err());
+        assert!(DecryptionKey::decrypt(&recipient, b"payload").await.is_err());
+    }
+}
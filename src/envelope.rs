@@ -0,0 +1,159 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Header of the data written by Purity.
+//!
+//! Every `MetadataFeature` written by Purity starts with `magic | version | kind`, so raw data,
+//! whatever its first bytes, is never mistaken for a chunk, a manifest or an envelope. Data
+//! without the header was written by someone else and is read as is.
+//!
+//! The body of a [`Kind::Payload`] starts with a byte of layer flags telling which envelopes
+//! wrap the data: compression and signature, or encryption alone, whose plaintext is again
+//! `flags | data` so the inner layers stay hidden from other readers.
+
+use crate::compression;
+use crate::encryption::{self, DecryptionKey};
+use crate::error::{PurityError, Result};
+use crate::signature::{self, Publisher};
+
+/// Magic bytes at the start of everything written by Purity.
+pub const ENVELOPE_MAGIC: [u8; 3] = *b"PUR";
+/// Version of the format, data of other versions is not decoded.
+pub const ENVELOPE_VERSION: u8 = 1;
+/// Length of the header: magic, version and kind.
+pub const HEADER_LEN: usize = ENVELOPE_MAGIC.len() + 2;
+
+/// The data is compressed, see [`compression`].
+pub const LAYER_COMPRESSED: u8 = 0b001;
+/// The data is signed, see [`signature`].
+pub const LAYER_SIGNED: u8 = 0b010;
+/// The data is encrypted, see [`encryption`].
+pub const LAYER_ENCRYPTED: u8 = 0b100;
+
+/// What a data output carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A payload, or the payload rebuilt from a manifest.
+    Payload,
+    /// A piece of a fragmented payload, see [`fragment`](crate::fragment).
+    Chunk,
+    /// The list of chunks of a fragmented payload.
+    Manifest,
+}
+
+impl Kind {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Payload => 0,
+            Self::Chunk => 1,
+            Self::Manifest => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Payload),
+            1 => Some(Self::Chunk),
+            2 => Some(Self::Manifest),
+            _ => None,
+        }
+    }
+}
+
+/// Prefixes `body` with the header of `kind`.
+pub fn wrap(kind: Kind, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + body.len());
+    data.extend_from_slice(&ENVELOPE_MAGIC);
+    data.push(ENVELOPE_VERSION);
+    data.push(kind.to_byte());
+    data.extend_from_slice(body);
+    data
+}
+
+/// The kind and body of `data`, `None` if it was not written by this version of Purity.
+pub fn parse(data: &[u8]) -> Option<(Kind, &[u8])> {
+    if data.len() < HEADER_LEN || data[..ENVELOPE_MAGIC.len()] != ENVELOPE_MAGIC {
+        return None;
+    }
+    if data[ENVELOPE_MAGIC.len()] != ENVELOPE_VERSION {
+        log::debug!("envelope version {} is not supported", data[ENVELOPE_MAGIC.len()]);
+        return None;
+    }
+    Kind::from_byte(data[ENVELOPE_MAGIC.len() + 1]).map(|kind| (kind, &data[HEADER_LEN..]))
+}
+
+/// Whether `data` has the header of `kind`.
+pub fn is_kind(data: &[u8], kind: Kind) -> bool {
+    parse(data).is_some_and(|(k, _)| k == kind)
+}
+
+/// Prefixes `data` with its layer flags, making a payload body.
+pub(crate) fn body(layers: u8, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1 + data.len());
+    body.push(layers);
+    body.extend_from_slice(data);
+    body
+}
+
+/// A payload opened as far as possible without a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Opened {
+    /// The data, with its signer when it was signed.
+    Plain { data: Vec<u8>, publisher: Option<Publisher> },
+    /// Encrypted data: the encryption envelope, to open with a [`DecryptionKey`].
+    Sealed(Vec<u8>),
+}
+
+/// Opens the data of an output written under `tag`.
+///
+/// Payloads lose their layers, data without a Purity header is returned as is.
+pub fn open(tag: &[u8], data: Vec<u8>) -> Result<Opened> {
+    match parse(&data) {
+        Some((Kind::Payload, body)) => open_body(tag, body),
+        Some((kind, _)) => Err(PurityError::InvalidData(format!("expected a payload, found a {kind:?}"))),
+        None => Ok(Opened::Plain { data, publisher: None }),
+    }
+}
+
+/// Decrypts the encryption envelope of a sealed payload with `key` and opens what it holds.
+pub async fn unseal(tag: &[u8], envelope: &[u8], key: &dyn DecryptionKey) -> Result<(Vec<u8>, Option<Publisher>)> {
+    match open_body(tag, &key.decrypt(envelope).await?)? {
+        Opened::Plain { data, publisher } => Ok((data, publisher)),
+        Opened::Sealed(_) => Err(PurityError::InvalidData("payload is encrypted twice".to_string())),
+    }
+}
+
+fn open_body(tag: &[u8], body: &[u8]) -> Result<Opened> {
+    let (&layers, data) = body
+        .split_first()
+        .ok_or_else(|| PurityError::InvalidData("payload has no layer flags".to_string()))?;
+    if layers & LAYER_ENCRYPTED != 0 {
+        if !encryption::is_encrypted(data) {
+            return Err(PurityError::InvalidData("payload flagged as encrypted is not".to_string()));
+        }
+        return Ok(Opened::Sealed(data.to_vec()));
+    }
+    let (publisher, data) = if layers & LAYER_SIGNED != 0 {
+        let (publisher, data) = signature::open(tag, data)?;
+        (Some(publisher), data)
+    } else {
+        (None, data.to_vec())
+    };
+    let data = if layers & LAYER_COMPRESSED != 0 {
+        compression::decompress(&data)?
+    } else {
+        data
+    };
+    Ok(Opened::Plain { data, publisher })
+}
@@ -0,0 +1,150 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use iota_sdk::client::node_api::error::Error as NodeApiError;
+use iota_sdk::client::api::input_selection::Error as InputSelectionError;
+use iota_sdk::types::block::{BlockId, Error as BlockError};
+
+use crate::confirmation::InclusionState;
+use crate::writer::WriteReceipt;
+
+/// Error coming from iota-sdk, kept as the source of a [`PurityError`].
+///
+/// Client and block errors are wrapped in the wallet error, so there is a single type to match on.
+pub type SdkError = iota_sdk::wallet::Error;
+
+pub type Result<T> = std::result::Result<T, PurityError>;
+
+/// Errors returned by the Purity API, one variant for each class of failure.
+#[derive(Debug, thiserror::Error)]
+pub enum PurityError {
+    /// The node can't be reached, failed to answer or didn't accept a block.
+    #[error("node unreachable")]
+    NodeUnreachable(#[source] Option<SdkError>),
+    /// The funds available can't cover the storage deposit of the outputs.
+    #[error("insufficient funds for the storage deposit")]
+    InsufficientFunds(#[source] SdkError),
+    /// The tag doesn't fit in a `TagFeature`.
+    #[error("tag too large")]
+    TagTooLarge(#[source] Option<SdkError>),
+    /// The metadata doesn't fit in a `MetadataFeature`, nor in a fragmented payload.
+    #[error("metadata too large")]
+    MetadataTooLarge(#[source] Option<SdkError>),
+    /// A required environment variable is not set.
+    #[error("missing environment variable {0}")]
+    MissingEnvVar(String, #[source] std::env::VarError),
+    /// A setting required by the operation is missing from the `PurityConfig`.
+    #[error("missing configuration {0}")]
+    MissingConfig(&'static str),
+    /// The transaction was sent but never got included in the ledger.
+    #[error("transaction not included")]
+    NotIncluded(#[source] SdkError),
+    /// The block of a write didn't reach the state its `Confirmation` waits for.
+    #[error("block {0} not confirmed: {1}")]
+    Unconfirmed(BlockId, InclusionState),
+    /// A batch write failed partway; `written` holds the receipt of each entry already written, in entry order.
+    #[error("batch write failed after {} records were written", .written.iter().flatten().count())]
+    PartialBatch {
+        written: Vec<Option<WriteReceipt>>,
+        #[source]
+        source: Box<PurityError>,
+    },
+    /// An operation didn't complete in time.
+    #[error("timed out: {0}")]
+    Timeout(String),
+    /// The requested output, alias or record doesn't exist.
+    #[error("{0} not found")]
+    NotFound(String),
+    /// The arguments of the call are not valid.
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    /// The secret manager can't be set up: bad mnemonic or seed, wrong Stronghold password.
+    #[error("invalid secret: {0}")]
+    InvalidSecret(String),
+    /// Data read from the ledger doesn't have the expected shape.
+    #[error("invalid data: {0}")]
+    InvalidData(String),
+    /// A value can't be serialized or deserialized as JSON.
+    #[error("serialization error")]
+    Serialization(#[source] serde_json::Error),
+    /// The local storage failed.
+    #[error("storage error")]
+    Storage(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// Any other iota-sdk error.
+    #[error(transparent)]
+    Sdk(SdkError),
+}
+
+impl From<SdkError> for PurityError {
+    fn from(error: SdkError) -> Self {
+        match &error {
+            SdkError::InsufficientFunds { .. } => Self::InsufficientFunds(error),
+            SdkError::Block(block_error) => match block_error.as_ref() {
+                BlockError::InvalidTagFeatureLength(_) => Self::TagTooLarge(Some(error)),
+                BlockError::InvalidMetadataFeatureLength(_) | BlockError::InvalidStateMetadataLength(_) => {
+                    Self::MetadataTooLarge(Some(error))
+                }
+                BlockError::InsufficientStorageDepositAmount { .. } => Self::InsufficientFunds(error),
+                _ => Self::Sdk(error),
+            },
+            SdkError::Client(client_error) => match client_error.as_ref() {
+                iota_sdk::client::Error::Node(NodeApiError::Reqwest(_))
+                | iota_sdk::client::Error::HealthyNodePoolEmpty => Self::NodeUnreachable(Some(error)),
+                iota_sdk::client::Error::Node(NodeApiError::ResponseError { code, .. }) if *code >= 500 => {
+                    Self::NodeUnreachable(Some(error))
+                }
+                iota_sdk::client::Error::InputSelection(InputSelectionError::InsufficientAmount { .. }) => {
+                    Self::InsufficientFunds(error)
+                }
+                iota_sdk::client::Error::Block(BlockError::InvalidTagFeatureLength(_)) => Self::TagTooLarge(Some(error)),
+                iota_sdk::client::Error::Block(
+                    BlockError::InvalidMetadataFeatureLength(_) | BlockError::InvalidStateMetadataLength(_),
+                ) => Self::MetadataTooLarge(Some(error)),
+                iota_sdk::client::Error::TangleInclusion(_) => Self::NotIncluded(error),
+                _ => Self::Sdk(error),
+            },
+            _ => Self::Sdk(error),
+        }
+    }
+}
+
+impl From<iota_sdk::client::Error> for PurityError {
+    fn from(error: iota_sdk::client::Error) -> Self {
+        SdkError::from(error).into()
+    }
+}
+
+impl From<BlockError> for PurityError {
+    fn from(error: BlockError) -> Self {
+        SdkError::from(error).into()
+    }
+}
+
+impl From<iota_sdk::client::stronghold::Error> for PurityError {
+    fn from(error: iota_sdk::client::stronghold::Error) -> Self {
+        SdkError::from(error).into()
+    }
+}
+
+impl From<rocksdb::Error> for PurityError {
+    fn from(error: rocksdb::Error) -> Self {
+        Self::Storage(Box::new(error))
+    }
+}
+
+impl From<serde_json::Error> for PurityError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Serialization(error)
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cost of a write, computed without sending anything.
+//!
+//! The outputs of the write are built as the write path would build them, with the live rent
+//! structure, so the deposit is exact. Signed and encrypted payloads are sized with their
+//! envelope overhead, compression is actually run.
+
+use iota_sdk::{
+    client::Client,
+    types::block::{
+        address::Bech32Address,
+        output::{
+            feature::{MetadataFeature, TagFeature},
+            OutputId, RentStructure,
+        },
+        payload::transaction::TransactionId,
+    },
+};
+
+use crate::compression;
+use crate::encryption::ENVELOPE_OVERHEAD;
+use crate::envelope;
+use crate::error::{PurityError, Result};
+use crate::fragment::{self, Manifest};
+use crate::options::WriteOptions;
+use crate::signature::SIGNATURE_OVERHEAD;
+use crate::writer::{chunk_output, data_output};
+
+/// Write path the estimate is for, they build slightly different outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WritePath {
+    /// `PurityAccountExt::write_data`.
+    Account,
+    /// `client::write_with_client`: the outputs carry a `SenderFeature`.
+    Client,
+}
+
+/// Expected cost of a write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteEstimate {
+    /// Payload size as given by the caller.
+    pub payload_len: usize,
+    /// Size written on the ledger, after compression, signature and encryption.
+    pub encoded_len: usize,
+    /// Outputs created, chunks and manifest included.
+    pub outputs: usize,
+    pub transactions: usize,
+    /// Storage deposit locked by the outputs.
+    pub storage_deposit: u64,
+    /// Price of a virtual byte in the live rent structure.
+    pub byte_cost: u32,
+    /// Why the write would be rejected by the protocol, `None` when it fits.
+    pub limit_exceeded: Option<String>,
+    /// Funds available to pay the deposit, when known.
+    pub available: Option<u64>,
+}
+
+impl WriteEstimate {
+    pub fn within_limits(&self) -> bool {
+        self.limit_exceeded.is_none()
+    }
+
+    /// Whether the available funds cover the deposit, `None` when the balance is unknown.
+    pub fn is_covered(&self) -> Option<bool> {
+        self.available.map(|available| available >= self.storage_deposit)
+    }
+
+    pub fn with_available(mut self, available: u64) -> Self {
+        self.available = Some(available);
+        self
+    }
+}
+
+/// Estimates a write of `payload` under `tag` to `address`, fetching the rent structure from the node.
+pub async fn estimate_write(
+    client: &Client,
+    path: WritePath,
+    address: &Bech32Address,
+    tag: &str,
+    payload: &[u8],
+    options: &WriteOptions,
+) -> Result<WriteEstimate> {
+    let rent_structure = client.get_rent_structure().await?;
+    let token_supply = client.get_token_supply().await?;
+    estimate(path, address, tag, payload, options, rent_structure, token_supply)
+}
+
+/// Estimates a write with a known rent structure and token supply.
+pub fn estimate(
+    path: WritePath,
+    address: &Bech32Address,
+    tag: &str,
+    payload: &[u8],
+    options: &WriteOptions,
+    rent_structure: RentStructure,
+    token_supply: u64,
+) -> Result<WriteEstimate> {
+    // Header and layer flags, see `WriteOptions::encode`
+    let mut encoded_len = envelope::HEADER_LEN + 1 + compression::compress(payload.to_vec(), options.compression)?.0.len();
+    if options.signer.is_some() {
+        encoded_len += SIGNATURE_OVERHEAD;
+    }
+    if options.recipient.is_some() {
+        encoded_len += 1 + ENVELOPE_OVERHEAD;
+    }
+
+    let mut estimate = WriteEstimate {
+        payload_len: payload.len(),
+        encoded_len,
+        outputs: 1,
+        transactions: 1,
+        storage_deposit: 0,
+        byte_cost: rent_structure.byte_cost(),
+        limit_exceeded: None,
+        available: None,
+    };
+
+    // Only the sizes matter for the deposit, the content is a placeholder
+    let encoded = vec![0u8; encoded_len];
+    let metadata = if fragment::needs_fragmentation(&encoded) {
+        let chunks = fragment::split(&encoded);
+        if chunks.len() > fragment::MAX_CHUNKS {
+            estimate.limit_exceeded = Some(format!(
+                "payload needs {} chunks, at most {} are supported", chunks.len(), fragment::MAX_CHUNKS
+            ));
+            return Ok(estimate);
+        }
+        estimate.outputs += chunks.len();
+        estimate.transactions += chunks.len().div_ceil(fragment::CHUNKS_PER_TRANSACTION);
+        for chunk in chunks.iter() {
+            estimate.storage_deposit += output_deposit(path, address, None, chunk.clone(), options, rent_structure, token_supply)?;
+        }
+        let placeholder = OutputId::new(TransactionId::null(), 0)?;
+        Manifest::new(&encoded, vec![placeholder; chunks.len()]).to_bytes()
+    } else {
+        encoded
+    };
+
+    match output_deposit(path, address, Some(tag), metadata, options, rent_structure, token_supply) {
+        Ok(deposit) => estimate.storage_deposit += deposit,
+        Err(PurityError::TagTooLarge(_)) => {
+            estimate.limit_exceeded = Some(format!(
+                "tag is {} B, at most {} B are allowed", tag.len(), TagFeature::LENGTH_RANGE.end()
+            ));
+        }
+        Err(PurityError::MetadataTooLarge(_)) => {
+            estimate.limit_exceeded = Some(format!(
+                "metadata is {} B, at most {} B fit in an output", encoded_len, MetadataFeature::LENGTH_RANGE.end()
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(estimate)
+}
+
+// Minimum storage deposit of a data output carrying `metadata`, a chunk when there is no tag
+fn output_deposit(
+    path: WritePath,
+    address: &Bech32Address,
+    tag: Option<&str>,
+    metadata: Vec<u8>,
+    options: &WriteOptions,
+    rent_structure: RentStructure,
+    token_supply: u64,
+) -> Result<u64> {
+    let options = options.clone().with_sender(options.sender || path == WritePath::Client);
+    let output = match tag {
+        Some(tag) => data_output(address, tag, metadata, &options, rent_structure, token_supply)?,
+        None => chunk_output(address, metadata, &options, rent_structure, token_supply)?,
+    };
+    Ok(output.amount())
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::client::constants::SHIMMER_COIN_TYPE;
+    use iota_sdk::crypto::keys::{bip44::Bip44, x25519::SecretKey};
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+
+    use super::*;
+    use crate::client::ClientWriter;
+    use crate::compression::Compression;
+    use crate::config::PurityConfig;
+    use crate::ledger::{Ledger, SimulatedLedger};
+    use crate::secret::{create_secret_manager, SecretSource};
+    use crate::writer::{PurityWriter, WriteRequest};
+
+    fn address() -> Bech32Address {
+        Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr")
+    }
+
+    #[tokio::test]
+    async fn estimate_matches_the_write() {
+        let secret_manager = create_secret_manager(SecretSource::HexSeed(hex::encode([5; 32]))).await.unwrap();
+        let config = PurityConfig::builder().with_node_url("simulated").finish().unwrap();
+        let writer = ClientWriter::new(SimulatedLedger::new(), secret_manager, config);
+        let (rent_structure, token_supply) =
+            (writer.ledger().rent_structure().await.unwrap(), writer.ledger().token_supply().await.unwrap());
+
+        let large: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let cases = [
+            (b"plain".to_vec(), WriteOptions::default()),
+            (
+                b"compressed ".repeat(50),
+                WriteOptions::default().with_compression(Compression::Deflate).with_signer(Bip44::new(SHIMMER_COIN_TYPE)),
+            ),
+            (b"sealed".to_vec(), WriteOptions::default().with_recipient(SecretKey::generate().unwrap().public_key())),
+            (large, WriteOptions::default()),
+        ];
+        for (payload, options) in cases {
+            let options = options.with_sender(true);
+            let estimate = estimate(WritePath::Client, &address(), "t", &payload, &options, rent_structure, token_supply).unwrap();
+            let receipt = writer.write(WriteRequest::new(address(), "t", payload, options)).await.unwrap();
+
+            assert!(estimate.within_limits());
+            assert_eq!(estimate.encoded_len, receipt.encoded_len);
+            assert_eq!(estimate.outputs, receipt.output_ids.len());
+            assert_eq!(estimate.storage_deposit, receipt.storage_deposit);
+        }
+    }
+
+    #[test]
+    fn protocol_limits_are_reported() {
+        let (options, rent_structure) = (WriteOptions::default(), RentStructure::default());
+        let supply = crate::ledger::SHIMMER_TOKEN_SUPPLY;
+        let long_tag = "t".repeat(65);
+        let tagged = estimate(WritePath::Account, &address(), &long_tag, b"data", &options, rent_structure, supply).unwrap();
+        assert!(!tagged.within_limits());
+
+        let huge = vec![0; (fragment::MAX_CHUNKS + 1) * fragment::CHUNK_DATA_LEN];
+        let fragmented = estimate(WritePath::Account, &address(), "t", &huge, &options, rent_structure, supply).unwrap();
+        assert!(fragmented.limit_exceeded.is_some_and(|reason| reason.contains("chunks")));
+    }
+
+    #[test]
+    fn deposit_is_compared_with_the_balance() {
+        let options = WriteOptions::default();
+        let small = estimate(WritePath::Account, &address(), "t", b"data", &options, RentStructure::default(), 1_000_000_000).unwrap();
+        assert_eq!(small.is_covered(), None);
+        assert_eq!(small.clone().with_available(small.storage_deposit).is_covered(), Some(true));
+        assert_eq!(small.clone().with_available(small.storage_deposit - 1).is_covered(), Some(false));
+    }
+}
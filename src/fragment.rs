@@ -0,0 +1,214 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting of payloads that don't fit in a single `MetadataFeature`.
+//!
+//! A fragmented payload is written as a list of chunk outputs followed by a manifest output.
+//! Every chunk carries a small header with its position, the manifest lists the chunk
+//! `OutputId`s in order together with the total length and the hash of the original bytes.
+//! Both start with the [`envelope`] header of their kind; chunks carry no tag, so they don't
+//! show up among the records of the tag.
+
+use iota_sdk::crypto::hashes::{blake2b::Blake2b256, Digest};
+use iota_sdk::types::block::output::{feature::MetadataFeature, OutputId};
+
+use crate::envelope::{self, Kind};
+use crate::error::{PurityError, Result};
+
+/// Chunk header: envelope header and the chunk index as a little-endian `u32`.
+pub const CHUNK_HEADER_LEN: usize = envelope::HEADER_LEN + 4;
+/// Largest slice of the payload carried by one chunk.
+pub const CHUNK_DATA_LEN: usize = *MetadataFeature::LENGTH_RANGE.end() as usize - CHUNK_HEADER_LEN;
+/// Chunks sent in the same transaction, a block can't be larger than 32 KiB.
+pub const CHUNKS_PER_TRANSACTION: usize = 3;
+
+// Manifest header: envelope header, total length (u64), hash (32 bytes) and the number of chunks (u16).
+const MANIFEST_HEADER_LEN: usize = envelope::HEADER_LEN + 8 + 32 + 2;
+/// Most chunks a manifest can list while still fitting in a single `MetadataFeature`.
+pub const MAX_CHUNKS: usize = (*MetadataFeature::LENGTH_RANGE.end() as usize - MANIFEST_HEADER_LEN) / OutputId::LENGTH;
+
+/// Whether `metadata` is too large to be written in a single output.
+pub fn needs_fragmentation(metadata: &[u8]) -> bool {
+    metadata.len() > *MetadataFeature::LENGTH_RANGE.end() as usize
+}
+
+/// Splits `payload` into chunks, each one ready to be used as the data of a `MetadataFeature`.
+pub fn split(payload: &[u8]) -> Vec<Vec<u8>> {
+    payload
+        .chunks(CHUNK_DATA_LEN)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut body = Vec::with_capacity(4 + data.len());
+            body.extend_from_slice(&(index as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            envelope::wrap(Kind::Chunk, &body)
+        })
+        .collect()
+}
+
+/// Whether `metadata` is a chunk of a fragmented payload.
+pub fn is_chunk(metadata: &[u8]) -> bool {
+    metadata.len() >= CHUNK_HEADER_LEN && envelope::is_kind(metadata, Kind::Chunk)
+}
+
+/// Whether `metadata` is the manifest of a fragmented payload.
+pub fn is_manifest(metadata: &[u8]) -> bool {
+    metadata.len() >= MANIFEST_HEADER_LEN && envelope::is_kind(metadata, Kind::Manifest)
+}
+
+/// Returns the index and the data carried by a chunk.
+pub fn parse_chunk(metadata: &[u8]) -> Result<(u32, &[u8])> {
+    if !is_chunk(metadata) {
+        return Err(PurityError::InvalidData("metadata is not a payload chunk".to_string()));
+    }
+    let index = u32::from_le_bytes(metadata[envelope::HEADER_LEN..CHUNK_HEADER_LEN].try_into().expect("header length checked"));
+    Ok((index, &metadata[CHUNK_HEADER_LEN..]))
+}
+
+/// Blake2b-256 hash of a payload.
+pub fn payload_hash(payload: &[u8]) -> [u8; 32] {
+    Blake2b256::digest(payload).into()
+}
+
+/// Describes how to rebuild a fragmented payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// Length of the original payload.
+    pub total_len: u64,
+    /// Blake2b-256 hash of the original payload.
+    pub hash: [u8; 32],
+    /// Chunk outputs, in payload order.
+    pub chunks: Vec<OutputId>,
+}
+
+impl Manifest {
+    pub fn new(payload: &[u8], chunks: Vec<OutputId>) -> Self {
+        Self {
+            total_len: payload.len() as u64,
+            hash: payload_hash(payload),
+            chunks,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MANIFEST_HEADER_LEN + self.chunks.len() * OutputId::LENGTH);
+        bytes.extend_from_slice(&self.total_len.to_le_bytes());
+        bytes.extend_from_slice(&self.hash);
+        bytes.extend_from_slice(&(self.chunks.len() as u16).to_le_bytes());
+        for output_id in &self.chunks {
+            bytes.extend_from_slice(output_id.transaction_id().as_ref());
+            bytes.extend_from_slice(&output_id.index().to_le_bytes());
+        }
+        envelope::wrap(Kind::Manifest, &bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !is_manifest(bytes) {
+            return Err(PurityError::InvalidData("metadata is not a payload manifest".to_string()));
+        }
+        // Lengths of the header fields are guaranteed by is_manifest
+        let mut offset = envelope::HEADER_LEN;
+        let total_len = u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("header length checked"));
+        offset += 8;
+        let hash: [u8; 32] = bytes[offset..offset + 32].try_into().expect("header length checked");
+        offset += 32;
+        let count = u16::from_le_bytes(bytes[offset..offset + 2].try_into().expect("header length checked")) as usize;
+        offset += 2;
+
+        let ids = &bytes[offset..];
+        if ids.len() != count * OutputId::LENGTH {
+            return Err(PurityError::InvalidData(format!(
+                "manifest lists {} chunks but carries {} bytes of output ids", count, ids.len()
+            )));
+        }
+        let chunks = ids
+            .chunks(OutputId::LENGTH)
+            .map(|id| Ok(OutputId::try_from(<[u8; OutputId::LENGTH]>::try_from(id).expect("exact chunks"))?))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { total_len, hash, chunks })
+    }
+
+    /// Rebuilds the original payload from the chunk metadata, given in manifest order,
+    /// and checks it against the recorded length and hash.
+    pub fn reassemble(&self, chunks: &[Vec<u8>]) -> Result<Vec<u8>> {
+        if chunks.len() != self.chunks.len() {
+            return Err(PurityError::InvalidData(format!("expected {} chunks, got {}", self.chunks.len(), chunks.len())));
+        }
+        let mut payload = Vec::with_capacity(self.total_len as usize);
+        for (expected, chunk) in chunks.iter().enumerate() {
+            let (index, data) = parse_chunk(chunk)?;
+            if index as usize != expected {
+                return Err(PurityError::InvalidData(format!("chunk {} found at position {}", index, expected)));
+            }
+            payload.extend_from_slice(data);
+        }
+        if payload.len() as u64 != self.total_len {
+            return Err(PurityError::InvalidData(format!(
+                "reassembled payload is {} bytes, manifest says {}", payload.len(), self.total_len
+            )));
+        }
+        if payload_hash(&payload) != self.hash {
+            return Err(PurityError::InvalidData("reassembled payload hash does not match the manifest".to_string()));
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::types::block::payload::transaction::TransactionId;
+
+    use super::*;
+
+    fn output_ids(count: usize) -> Vec<OutputId> {
+        (0..count as u16).map(|i| OutputId::new(TransactionId::new([7; 32]), i).unwrap()).collect()
+    }
+
+    #[test]
+    fn split_payload_is_reassembled_through_the_manifest() {
+        let payload: Vec<u8> = (0..3 * CHUNK_DATA_LEN + 10).map(|i| i as u8).collect();
+        let chunks = split(&payload);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|c| is_chunk(c) && !needs_fragmentation(c)));
+
+        let bytes = Manifest::new(&payload, output_ids(chunks.len())).to_bytes();
+        assert!(is_manifest(&bytes) && !is_chunk(&bytes));
+        let manifest = Manifest::from_bytes(&bytes).unwrap();
+        assert_eq!(manifest.chunks, output_ids(4));
+        assert_eq!(manifest.reassemble(&chunks).unwrap(), payload);
+    }
+
+    #[test]
+    fn tampered_or_misplaced_chunks_are_rejected() {
+        let payload = vec![1; 2 * CHUNK_DATA_LEN];
+        let chunks = split(&payload);
+        let manifest = Manifest::new(&payload, output_ids(chunks.len()));
+
+        let mut tampered = chunks.clone();
+        *tampered[1].last_mut().unwrap() ^= 1;
+        assert!(matches!(manifest.reassemble(&tampered), Err(PurityError::InvalidData(_))));
+
+        let swapped = vec![chunks[1].clone(), chunks[0].clone()];
+        assert!(manifest.reassemble(&swapped).is_err());
+        assert!(manifest.reassemble(&chunks[..1]).is_err());
+    }
+
+    #[test]
+    fn truncated_manifest_is_rejected() {
+        let bytes = Manifest::new(b"payload", output_ids(2)).to_bytes();
+        assert!(Manifest::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Manifest::from_bytes(b"payload").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The ledger operations Purity needs, behind a trait.
+//!
+//! [`Ledger`] is implemented for the node [`Client`] and for [`SimulatedLedger`], an in-memory
+//! ledger that lets the encoding, write and read logic run without a node.
+
+pub use simulated::{RemainderPosition, SimulatedLedger, SHIMMER_TOKEN_SUPPLY};
+
+mod simulated;
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use iota_sdk::{
+    client::{secret::SecretManager, Client},
+    types::api::core::response::{BlockMetadataResponse, LedgerInclusionState},
+    types::block::{
+        address::{Bech32Address, Hrp},
+        output::{Output, OutputId, OutputWithMetadata, RentStructure},
+        payload::{transaction::TransactionId, Payload},
+        BlockId,
+    },
+};
+
+use crate::client::{read_all_pages, PageOptions};
+use crate::confirmation::{Confirmation, InclusionState};
+use crate::error::{PurityError, Result};
+use crate::timing::{Phase, WriteTimings};
+
+// Time between two checks of a block state
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A block sent to the ledger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentBlock {
+    pub block_id: BlockId,
+    /// Transaction carried by the block, its outputs are `OutputId::new(transaction_id, index)`.
+    pub transaction_id: TransactionId,
+    /// Outputs created by the transaction, in order.
+    pub outputs: Vec<Output>,
+    /// Block build and submission times.
+    pub timings: WriteTimings,
+}
+
+/// Operations on the ledger used by the write and read logic.
+#[async_trait]
+pub trait Ledger: Send + Sync {
+    async fn rent_structure(&self) -> Result<RentStructure>;
+
+    async fn token_supply(&self) -> Result<u64>;
+
+    async fn bech32_hrp(&self) -> Result<Hrp>;
+
+    /// Ids of the unspent basic outputs under `tag`, only those owned by `address` if given.
+    async fn basic_output_ids(&self, tag: &str, address: Option<&Bech32Address>) -> Result<Vec<OutputId>>;
+
+    /// The outputs with their metadata, in the order of `output_ids`.
+    async fn get_outputs(&self, output_ids: &[OutputId]) -> Result<Vec<OutputWithMetadata>>;
+
+    async fn get_output(&self, output_id: &OutputId) -> Result<OutputWithMetadata> {
+        self.get_outputs(&[*output_id])
+            .await?
+            .pop()
+            .ok_or_else(|| PurityError::NotFound(format!("output {output_id}")))
+    }
+
+    /// Builds a transaction creating `outputs`, funded and signed by `secret_manager`, and sends it in a block.
+    async fn send_outputs(&self, secret_manager: &SecretManager, outputs: Vec<Output>) -> Result<SentBlock>;
+
+    /// Waits for the block as `confirmation` asks, up to its timeout.
+    ///
+    /// Returns the block holding the transaction, a reattachment of `block_id` if it had to be
+    /// replaced, and its state when the wait ended. The block is already sent, a node failing
+    /// while it is checked ends the wait like the timeout does.
+    async fn confirm(&self, block_id: &BlockId, confirmation: Confirmation) -> Result<(BlockId, InclusionState)>;
+}
+
+#[async_trait]
+impl Ledger for Client {
+    async fn rent_structure(&self) -> Result<RentStructure> {
+        Ok(self.get_rent_structure().await?)
+    }
+
+    async fn token_supply(&self) -> Result<u64> {
+        Ok(self.get_token_supply().await?)
+    }
+
+    async fn bech32_hrp(&self) -> Result<Hrp> {
+        Ok(self.get_bech32_hrp().await?)
+    }
+
+    async fn basic_output_ids(&self, tag: &str, address: Option<&Bech32Address>) -> Result<Vec<OutputId>> {
+        read_all_pages(self, tag, address, PageOptions::default()).await
+    }
+
+    async fn get_outputs(&self, output_ids: &[OutputId]) -> Result<Vec<OutputWithMetadata>> {
+        Ok(Client::get_outputs(self, output_ids).await?)
+    }
+
+    async fn send_outputs(&self, secret_manager: &SecretManager, outputs: Vec<Output>) -> Result<SentBlock> {
+        // The steps of `ClientBlockBuilder::finish`, timed one by one
+        let builder = self.build_block().with_secret_manager(secret_manager).with_outputs(outputs)?;
+        let mut timings = WriteTimings::default();
+        let transaction = timings
+            .measure_async(Phase::BlockBuild, async {
+                let prepared = builder.prepare_transaction().await?;
+                builder.sign_transaction(prepared).await
+            })
+            .await?;
+        let block = timings
+            .measure_async(Phase::Submission, builder.finish_block(Some(Payload::from(transaction))))
+            .await?;
+        let Some(Payload::Transaction(transaction)) = block.payload() else {
+            return Err(PurityError::InvalidData(format!("block {} carries no transaction", block.id())));
+        };
+        Ok(SentBlock {
+            block_id: block.id(),
+            transaction_id: transaction.id(),
+            outputs: transaction.essence().as_regular().outputs().to_vec(),
+            timings,
+        })
+    }
+
+    async fn confirm(&self, block_id: &BlockId, confirmation: Confirmation) -> Result<(BlockId, InclusionState)> {
+        Ok(wait_for_block(self, block_id, confirmation, POLL_INTERVAL).await)
+    }
+}
+
+// The node calls made while waiting for a block
+#[async_trait]
+trait BlockNode: Sync {
+    async fn block_metadata(&self, block_id: &BlockId) -> Result<BlockMetadataResponse>;
+
+    async fn promote(&self, block_id: &BlockId) -> Result<()>;
+
+    // Sends the payload of the block again, in the returned block
+    async fn reattach(&self, block_id: &BlockId) -> Result<BlockId>;
+}
+
+#[async_trait]
+impl BlockNode for Client {
+    async fn block_metadata(&self, block_id: &BlockId) -> Result<BlockMetadataResponse> {
+        Ok(self.get_block_metadata(block_id).await?)
+    }
+
+    async fn promote(&self, block_id: &BlockId) -> Result<()> {
+        self.promote_unchecked(block_id).await?;
+        Ok(())
+    }
+
+    async fn reattach(&self, block_id: &BlockId) -> Result<BlockId> {
+        Ok(self.reattach_unchecked(block_id).await?.0)
+    }
+}
+
+// Polls the block until its state satisfies `confirmation` or the timeout elapses.
+//
+// The block is already sent, so a failed check ends the wait like the timeout does, with the
+// last state seen: the write is done and its receipt tells the caller where it stands.
+async fn wait_for_block(
+    node: &impl BlockNode,
+    block_id: &BlockId,
+    confirmation: Confirmation,
+    poll_interval: Duration,
+) -> (BlockId, InclusionState) {
+    let Some(timeout) = confirmation.timeout() else {
+        return (*block_id, InclusionState::Pending);
+    };
+    // Like `Client::retry_until_included`, any attachment can end up holding the transaction
+    let mut attachments = vec![*block_id];
+    let mut last = (*block_id, InclusionState::Pending);
+    let wait = async {
+        loop {
+            let mut conflicting = 0;
+            for (index, id) in attachments.clone().iter().enumerate() {
+                let metadata = node.block_metadata(id).await?;
+                let state = inclusion_state(&metadata);
+                if state.satisfies(&confirmation) {
+                    return Ok::<_, PurityError>((*id, state));
+                }
+                if matches!(state, InclusionState::Conflicting { .. }) {
+                    conflicting += 1;
+                }
+                if index + 1 < attachments.len() {
+                    continue;
+                }
+                last = (*id, state);
+                if matches!(confirmation, Confirmation::Milestone { .. }) && !state.is_final() {
+                    if metadata.should_promote.unwrap_or(false) {
+                        node.promote(id).await?;
+                    } else if metadata.should_reattach.unwrap_or(false) {
+                        let reattached = node.reattach(id).await?;
+                        log::info!("Block {id} reattached in {reattached}");
+                        attachments.push(reattached);
+                    }
+                }
+            }
+            if conflicting == attachments.len() {
+                return Ok(last);
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    };
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(Ok(done)) => done,
+        Ok(Err(e)) => {
+            log::warn!("Block {} still {}, checking it failed: {e}", last.0, last.1);
+            last
+        }
+        Err(_) => {
+            log::warn!("Block {} still {} after {:?}", last.0, last.1, timeout);
+            last
+        }
+    }
+}
+
+fn inclusion_state(metadata: &BlockMetadataResponse) -> InclusionState {
+    match (metadata.referenced_by_milestone_index, metadata.ledger_inclusion_state) {
+        (Some(milestone_index), Some(LedgerInclusionState::Conflicting)) => InclusionState::Conflicting {
+            milestone_index,
+            reason: metadata.conflict_reason.unwrap_or_default(),
+        },
+        (Some(milestone_index), Some(_)) => InclusionState::Confirmed { milestone_index },
+        _ if metadata.is_solid => InclusionState::Solid,
+        _ => InclusionState::Pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex, time::Instant};
+
+    use super::*;
+
+    const BLOCK: [u8; 32] = [1; 32];
+    const REATTACHED: [u8; 32] = [2; 32];
+
+    // Answers the checks with the scripted metadata in turn, `None` being a node failure; the last
+    // answer is repeated
+    struct ScriptedNode {
+        answers: Mutex<VecDeque<Option<BlockMetadataResponse>>>,
+    }
+
+    impl ScriptedNode {
+        fn new(answers: impl IntoIterator<Item = Option<BlockMetadataResponse>>) -> Self {
+            Self {
+                answers: Mutex::new(answers.into_iter().collect()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl BlockNode for ScriptedNode {
+        async fn block_metadata(&self, _block_id: &BlockId) -> Result<BlockMetadataResponse> {
+            let mut answers = self.answers.lock().unwrap();
+            let answer = if answers.len() > 1 { answers.pop_front() } else { answers.front().cloned() };
+            answer.flatten().ok_or(PurityError::NodeUnreachable(None))
+        }
+
+        async fn promote(&self, _block_id: &BlockId) -> Result<()> {
+            Ok(())
+        }
+
+        async fn reattach(&self, _block_id: &BlockId) -> Result<BlockId> {
+            Ok(BlockId::new(REATTACHED))
+        }
+    }
+
+    fn metadata(is_solid: bool, milestone_index: Option<u32>, should_reattach: bool) -> Option<BlockMetadataResponse> {
+        Some(BlockMetadataResponse {
+            block_id: BlockId::new(BLOCK),
+            parents: Vec::new(),
+            is_solid,
+            referenced_by_milestone_index: milestone_index,
+            milestone_index: None,
+            ledger_inclusion_state: milestone_index.map(|_| LedgerInclusionState::Included),
+            conflict_reason: None,
+            white_flag_index: None,
+            should_promote: None,
+            should_reattach: Some(should_reattach),
+        })
+    }
+
+    fn milestone(millis: u64) -> Confirmation {
+        Confirmation::Milestone { timeout: Duration::from_millis(millis) }
+    }
+
+    async fn wait(node: &ScriptedNode, confirmation: Confirmation) -> (BlockId, InclusionState) {
+        wait_for_block(node, &BlockId::new(BLOCK), confirmation, Duration::from_millis(5)).await
+    }
+
+    #[tokio::test]
+    async fn block_is_polled_until_confirmed() {
+        let node = ScriptedNode::new([metadata(false, None, false), metadata(true, None, false), metadata(true, Some(7), false)]);
+        assert_eq!(wait(&node, milestone(5_000)).await, (BlockId::new(BLOCK), InclusionState::Confirmed { milestone_index: 7 }));
+
+        let node = ScriptedNode::new([metadata(true, None, false)]);
+        assert_eq!(wait(&node, Confirmation::inclusion()).await, (BlockId::new(BLOCK), InclusionState::Solid));
+        assert_eq!(wait(&node, Confirmation::None).await, (BlockId::new(BLOCK), InclusionState::Pending));
+    }
+
+    #[tokio::test]
+    async fn reattachment_is_followed() {
+        let node = ScriptedNode::new([metadata(false, None, true), metadata(false, None, false), metadata(true, Some(3), false)]);
+        assert_eq!(wait(&node, milestone(5_000)).await, (BlockId::new(REATTACHED), InclusionState::Confirmed { milestone_index: 3 }));
+    }
+
+    #[tokio::test]
+    async fn timeout_returns_the_last_state() {
+        let node = ScriptedNode::new([metadata(true, None, false)]);
+        let start = Instant::now();
+        assert_eq!(wait(&node, milestone(50)).await, (BlockId::new(BLOCK), InclusionState::Solid));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn failed_check_returns_the_last_state() {
+        let node = ScriptedNode::new([metadata(true, None, false), None]);
+        let start = Instant::now();
+        assert_eq!(wait(&node, milestone(5_000)).await, (BlockId::new(BLOCK), InclusionState::Solid));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let node = ScriptedNode::new([None]);
+        assert_eq!(wait(&node, milestone(5_000)).await, (BlockId::new(BLOCK), InclusionState::Pending));
+    }
+}
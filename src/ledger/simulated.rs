@@ -0,0 +1,268 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-memory ledger for offline runs.
+//!
+//! Sent outputs are booked at once, each block confirmed by its own milestone, and indexed like
+//! the node indexer would. Nothing is signed, no input is consumed and funds are not checked; the
+//! outputs themselves are built and validated by the same code as on a real node. Like a wallet,
+//! each transaction also creates a remainder output, by default ahead of the sent outputs.
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use iota_sdk::{
+    client::secret::SecretManager,
+    types::block::{
+        address::{Address, Bech32Address, Ed25519Address, Hrp},
+        output::{
+            unlock_condition::AddressUnlockCondition, BasicOutputBuilder, Output, OutputId, OutputMetadata,
+            OutputWithMetadata, RentStructure,
+        },
+        payload::transaction::TransactionId,
+        BlockId,
+    },
+};
+
+use crate::confirmation::{Confirmation, InclusionState};
+use crate::error::{PurityError, Result};
+use crate::fragment::payload_hash;
+use crate::timing::WriteTimings;
+
+use super::{Ledger, SentBlock};
+
+/// Token supply of the Shimmer network, used by default.
+pub const SHIMMER_TOKEN_SUPPLY: u64 = 1_813_620_509_061_365;
+
+/// Where the remainder output goes among the outputs of a sent transaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RemainderPosition {
+    /// No remainder, the transaction creates the sent outputs only.
+    None,
+    /// Before the sent outputs, so no data output is at index 0.
+    #[default]
+    First,
+    /// After the sent outputs.
+    Last,
+}
+
+/// Ledger kept in memory, see the module documentation for what it simulates.
+#[derive(Debug)]
+pub struct SimulatedLedger {
+    rent_structure: RentStructure,
+    token_supply: u64,
+    hrp: Hrp,
+    remainder: RemainderPosition,
+    // Sends accepted before the node becomes unreachable
+    send_limit: Option<usize>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Ordered by id, like the pages of the indexer
+    outputs: BTreeMap<OutputId, OutputWithMetadata>,
+    // Milestone confirming each block
+    blocks: BTreeMap<BlockId, u32>,
+    milestone_index: u32,
+    sends: usize,
+}
+
+impl Default for SimulatedLedger {
+    fn default() -> Self {
+        Self {
+            rent_structure: RentStructure::default(),
+            token_supply: SHIMMER_TOKEN_SUPPLY,
+            hrp: Hrp::from_str_unchecked("smr"),
+            remainder: RemainderPosition::default(),
+            send_limit: None,
+            state: Mutex::default(),
+        }
+    }
+}
+
+impl SimulatedLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rent_structure(mut self, rent_structure: RentStructure) -> Self {
+        self.rent_structure = rent_structure;
+        self
+    }
+
+    pub fn with_token_supply(mut self, token_supply: u64) -> Self {
+        self.token_supply = token_supply;
+        self
+    }
+
+    pub fn with_hrp(mut self, hrp: Hrp) -> Self {
+        self.hrp = hrp;
+        self
+    }
+
+    pub fn with_remainder(mut self, remainder: RemainderPosition) -> Self {
+        self.remainder = remainder;
+        self
+    }
+
+    /// Accepts the first `sends` transactions, the next ones fail as if the node were unreachable.
+    pub fn with_send_limit(mut self, sends: usize) -> Self {
+        self.send_limit = Some(sends);
+        self
+    }
+
+    /// Books `outputs` in a new milestone, in this order, as a transaction sent in a block would.
+    pub fn book(&self, outputs: Vec<Output>) -> Result<SentBlock> {
+        let mut state = self.state.lock().expect("simulated ledger lock poisoned");
+        state.milestone_index += 1;
+        let index = state.milestone_index;
+        let seed = [index.to_le_bytes().as_slice(), &(outputs.len() as u32).to_le_bytes()].concat();
+        let transaction_id = TransactionId::new(payload_hash(&[b"transaction".as_slice(), &seed].concat()));
+        let block_id = BlockId::new(payload_hash(&[b"block".as_slice(), &seed].concat()));
+        let timestamp = now();
+        state.blocks.insert(block_id, index);
+
+        for (output_index, output) in outputs.iter().cloned().enumerate() {
+            let output_id = OutputId::new(transaction_id, output_index as u16)?;
+            let metadata = OutputMetadata::new(block_id, output_id, false, None, None, None, index, timestamp, index);
+            state.outputs.insert(output_id, OutputWithMetadata::new(output, metadata));
+        }
+        Ok(SentBlock {
+            block_id,
+            transaction_id,
+            outputs,
+            timings: WriteTimings::default(),
+        })
+    }
+
+    /// Marks an output as spent, it stays readable by id but leaves the indexer.
+    pub fn spend(&self, output_id: &OutputId) -> Result<()> {
+        let mut state = self.state.lock().expect("simulated ledger lock poisoned");
+        state.milestone_index += 1;
+        let index = state.milestone_index;
+        let output = state
+            .outputs
+            .get_mut(output_id)
+            .ok_or_else(|| PurityError::NotFound(format!("output {output_id}")))?;
+        let metadata = output.metadata();
+        let spent = OutputMetadata::new(
+            *metadata.block_id(),
+            *output_id,
+            true,
+            Some(index),
+            Some(now()),
+            Some(TransactionId::new(payload_hash(&index.to_le_bytes()))),
+            metadata.milestone_index_booked(),
+            metadata.milestone_timestamp_booked(),
+            index,
+        );
+        *output = OutputWithMetadata::new(output.output().clone(), spent);
+        Ok(())
+    }
+
+    // Remainder back to the owner of the first sent output, with the minimum deposit
+    fn remainder_output(&self, outputs: &[Output]) -> Result<Output> {
+        let address = outputs
+            .iter()
+            .find_map(|o| o.unlock_conditions().and_then(|u| u.address()).map(|a| *a.address()))
+            .unwrap_or(Address::Ed25519(Ed25519Address::new([0; Ed25519Address::LENGTH])));
+        Ok(BasicOutputBuilder::new_with_minimum_storage_deposit(self.rent_structure)
+            .add_unlock_condition(AddressUnlockCondition::new(address))
+            .finish_output(self.token_supply)?)
+    }
+}
+
+#[async_trait]
+impl Ledger for SimulatedLedger {
+    async fn rent_structure(&self) -> Result<RentStructure> {
+        Ok(self.rent_structure)
+    }
+
+    async fn token_supply(&self) -> Result<u64> {
+        Ok(self.token_supply)
+    }
+
+    async fn bech32_hrp(&self) -> Result<Hrp> {
+        Ok(self.hrp)
+    }
+
+    async fn basic_output_ids(&self, tag: &str, address: Option<&Bech32Address>) -> Result<Vec<OutputId>> {
+        let state = self.state.lock().expect("simulated ledger lock poisoned");
+        let output_ids = state
+            .outputs
+            .iter()
+            .filter(|(_, o)| !o.metadata().is_spent())
+            .filter(|(_, o)| {
+                let Output::Basic(basic) = o.output() else { return false };
+                let tagged = basic.features().tag().is_some_and(|t| t.tag() == tag.as_bytes());
+                let owned = address.is_none_or(|a| basic.address() == a.inner());
+                tagged && owned
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        Ok(output_ids)
+    }
+
+    async fn get_outputs(&self, output_ids: &[OutputId]) -> Result<Vec<OutputWithMetadata>> {
+        let state = self.state.lock().expect("simulated ledger lock poisoned");
+        output_ids
+            .iter()
+            .map(|id| {
+                state
+                    .outputs
+                    .get(id)
+                    .cloned()
+                    .ok_or_else(|| PurityError::NotFound(format!("output {id}")))
+            })
+            .collect()
+    }
+
+    async fn send_outputs(&self, _secret_manager: &SecretManager, mut outputs: Vec<Output>) -> Result<SentBlock> {
+        {
+            let mut state = self.state.lock().expect("simulated ledger lock poisoned");
+            if self.send_limit.is_some_and(|limit| state.sends == limit) {
+                return Err(PurityError::NodeUnreachable(None));
+            }
+            state.sends += 1;
+        }
+        match self.remainder {
+            RemainderPosition::None => {}
+            RemainderPosition::First => outputs.insert(0, self.remainder_output(&outputs)?),
+            RemainderPosition::Last => outputs.push(self.remainder_output(&outputs)?),
+        }
+        self.book(outputs)
+    }
+
+    async fn confirm(&self, block_id: &BlockId, confirmation: Confirmation) -> Result<(BlockId, InclusionState)> {
+        if confirmation == Confirmation::None {
+            return Ok((*block_id, InclusionState::Pending));
+        }
+        let state = self.state.lock().expect("simulated ledger lock poisoned");
+        let milestone_index = state
+            .blocks
+            .get(block_id)
+            .copied()
+            .ok_or_else(|| PurityError::NotFound(format!("block {block_id}")))?;
+        Ok((*block_id, InclusionState::Confirmed { milestone_index }))
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or_default()
+}
@@ -13,5 +13,30 @@
 // limitations under the License.
 
 pub mod account;
+pub mod bench;
+pub mod channel;
 pub mod client;
-pub mod utils;
\ No newline at end of file
+pub mod compression;
+pub mod confirmation;
+pub mod config;
+pub mod encryption;
+pub mod envelope;
+pub mod error;
+pub mod estimate;
+pub mod fragment;
+pub mod ledger;
+pub mod options;
+pub mod policy;
+pub mod record;
+pub mod secret;
+pub mod signature;
+pub mod storage;
+pub mod timing;
+pub mod utils;
+pub mod writer;
+
+pub use config::PurityConfig;
+pub use confirmation::{Confirmation, InclusionState};
+pub use error::{PurityError, Result};
+pub use options::WriteOptions;
+pub use writer::{PurityWriter, WriteReceipt, WriteRequest};
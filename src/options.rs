@@ -0,0 +1,105 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Settings of a single write, shared by the account and client write paths.
+
+use iota_sdk::client::secret::SecretManager;
+use iota_sdk::crypto::keys::{bip44::Bip44, x25519::PublicKey};
+
+use crate::compression::{self, Compression};
+use crate::confirmation::Confirmation;
+use crate::encryption;
+use crate::envelope::{self, Kind};
+use crate::error::Result;
+use crate::policy::UnlockPolicy;
+use crate::signature;
+
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Unlock conditions of the data outputs.
+    pub policy: UnlockPolicy,
+    /// Seals the payload for this X25519 public key, see [`encryption`].
+    pub recipient: Option<PublicKey>,
+    /// Signs the payload with the Ed25519 key at this path of the secret manager, see [`signature`].
+    pub signer: Option<Bip44>,
+    /// Compression applied to the payload, see [`compression`].
+    pub compression: Compression,
+    /// Adds a `SenderFeature` with the target address, which the writer must own.
+    pub sender: bool,
+    /// What the write waits for once the block is sent.
+    pub confirmation: Confirmation,
+}
+
+impl WriteOptions {
+    pub fn with_policy(mut self, policy: UnlockPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_recipient(mut self, recipient: PublicKey) -> Self {
+        self.recipient = Some(recipient);
+        self
+    }
+
+    pub fn with_signer(mut self, chain: Bip44) -> Self {
+        self.signer = Some(chain);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_sender(mut self, sender: bool) -> Self {
+        self.sender = sender;
+        self
+    }
+
+    pub fn with_confirmation(mut self, confirmation: Confirmation) -> Self {
+        self.confirmation = confirmation;
+        self
+    }
+
+    /// Turns the caller payload into the bytes written on the ledger.
+    ///
+    /// The payload is compressed, signed and then encrypted, so the signature is only visible to the reader,
+    /// and the result goes in an [`envelope`] naming the layers applied.
+    pub(crate) async fn encode(&self, secret_manager: &SecretManager, tag: &[u8], payload: Vec<u8>) -> Result<Vec<u8>> {
+        let (payload, compressed) = compression::compress(payload, self.compression)?;
+        let mut layers = if compressed { envelope::LAYER_COMPRESSED } else { 0 };
+        let payload = match self.signer {
+            Some(chain) => {
+                layers |= envelope::LAYER_SIGNED;
+                signature::sign(secret_manager, chain, tag, &payload).await?
+            }
+            None => payload,
+        };
+        let body = envelope::body(layers, &payload);
+        let body = match &self.recipient {
+            Some(recipient) => envelope::body(envelope::LAYER_ENCRYPTED, &encryption::encrypt(recipient, &body)?),
+            None => body,
+        };
+        Ok(envelope::wrap(Kind::Payload, &body))
+    }
+}
+
+impl From<UnlockPolicy> for WriteOptions {
+    fn from(policy: UnlockPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+}
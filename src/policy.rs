@@ -0,0 +1,143 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use iota_sdk::types::block::{
+    address::Bech32Address,
+    output::{
+        unlock_condition::{
+            AddressUnlockCondition,
+            ExpirationUnlockCondition,
+            StorageDepositReturnUnlockCondition,
+            TimelockUnlockCondition,
+            UnlockCondition,
+        },
+        MinimumStorageDepositBasicOutput, RentStructure,
+    },
+};
+
+use crate::error::{PurityError, Result};
+
+/// How a data output can be unlocked, shared by the account and the client write paths.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum UnlockPolicy {
+    /// Only the address unlock condition, the data output can be spent right away.
+    #[default]
+    None,
+    /// The data output can't be spent before the given unix timestamp (seconds).
+    Timelock { unix_time: u32 },
+    /// After the given unix timestamp (seconds) only the return address can unlock the data output.
+    Expiration { return_address: Bech32Address, unix_time: u32 },
+    /// Whoever consumes the data output must send the storage deposit back to the return address.
+    StorageDepositReturn { return_address: Bech32Address },
+}
+
+impl UnlockPolicy {
+    /// Locks the data output for `duration` starting from now.
+    pub fn timelock_in(duration: Duration) -> Result<Self> {
+        Ok(Self::Timelock { unix_time: unix_time_in(duration)? })
+    }
+
+    /// Makes the data output expire to `return_address` after `duration` starting from now.
+    pub fn expiration_in(return_address: Bech32Address, duration: Duration) -> Result<Self> {
+        Ok(Self::Expiration { return_address, unix_time: unix_time_in(duration)? })
+    }
+
+    /// Builds the unlock conditions of a data output owned by `address`.
+    pub fn unlock_conditions(
+        &self,
+        address: &Bech32Address,
+        rent_structure: RentStructure,
+        token_supply: u64,
+    ) -> Result<Vec<UnlockCondition>> {
+        let mut unlock_conditions = vec![UnlockCondition::Address(AddressUnlockCondition::new(address))];
+        match self {
+            UnlockPolicy::None => {}
+            UnlockPolicy::Timelock { unix_time } => {
+                unlock_conditions.push(UnlockCondition::Timelock(TimelockUnlockCondition::new(*unix_time)?));
+            }
+            UnlockPolicy::Expiration { return_address, unix_time } => {
+                unlock_conditions.push(UnlockCondition::Expiration(ExpirationUnlockCondition::new(return_address, *unix_time)?));
+            }
+            UnlockPolicy::StorageDepositReturn { return_address } => {
+                // The returned amount must at least cover a simple output on the return address
+                let amount = MinimumStorageDepositBasicOutput::new(rent_structure, token_supply).finish()?;
+                unlock_conditions.push(UnlockCondition::StorageDepositReturn(
+                    StorageDepositReturnUnlockCondition::new(return_address, amount, token_supply)?,
+                ));
+            }
+        }
+        Ok(unlock_conditions)
+    }
+}
+
+fn unix_time_in(duration: Duration) -> Result<u32> {
+    (SystemTime::now() + duration)
+        .duration_since(UNIX_EPOCH)
+        .expect("clock went backwards")
+        .as_secs()
+        .try_into()
+        .map_err(|_| PurityError::InvalidInput(format!("unlock time {duration:?} from now is out of range")))
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::types::block::address::{Address, Ed25519Address, ToBech32Ext};
+
+    use super::*;
+    use crate::ledger::SHIMMER_TOKEN_SUPPLY;
+
+    fn address(byte: u8) -> Bech32Address {
+        Address::Ed25519(Ed25519Address::new([byte; 32])).to_bech32_unchecked("smr")
+    }
+
+    fn conditions(policy: UnlockPolicy) -> Result<Vec<UnlockCondition>> {
+        policy.unlock_conditions(&address(1), RentStructure::default(), SHIMMER_TOKEN_SUPPLY)
+    }
+
+    #[test]
+    fn each_policy_adds_its_unlock_condition() {
+        let owner = UnlockCondition::Address(AddressUnlockCondition::new(address(1)));
+        assert_eq!(conditions(UnlockPolicy::None).unwrap(), std::slice::from_ref(&owner));
+
+        let timelock = conditions(UnlockPolicy::Timelock { unix_time: 100 }).unwrap();
+        assert_eq!(timelock, [owner.clone(), UnlockCondition::Timelock(TimelockUnlockCondition::new(100).unwrap())]);
+
+        let expiration = conditions(UnlockPolicy::Expiration { return_address: address(2), unix_time: 100 }).unwrap();
+        assert_eq!(expiration[0], owner);
+        let UnlockCondition::Expiration(expiration) = &expiration[1] else { panic!("expected an expiration") };
+        assert_eq!((expiration.return_address(), expiration.timestamp()), (address(2).inner(), 100));
+
+        let deposit = conditions(UnlockPolicy::StorageDepositReturn { return_address: address(2) }).unwrap();
+        let UnlockCondition::StorageDepositReturn(deposit) = &deposit[1] else { panic!("expected a deposit return") };
+        let minimum = MinimumStorageDepositBasicOutput::new(RentStructure::default(), SHIMMER_TOKEN_SUPPLY).finish().unwrap();
+        assert_eq!((deposit.return_address(), deposit.amount()), (address(2).inner(), minimum));
+    }
+
+    #[test]
+    fn unlock_times_are_in_the_future_and_never_zero() {
+        let now = unix_time_in(Duration::ZERO).unwrap();
+        let UnlockPolicy::Timelock { unix_time } = UnlockPolicy::timelock_in(Duration::from_secs(60)).unwrap() else {
+            panic!("expected a timelock");
+        };
+        assert!(unix_time >= now + 60);
+        assert!(conditions(UnlockPolicy::Timelock { unix_time: 0 }).is_err());
+        assert!(conditions(UnlockPolicy::Expiration { return_address: address(2), unix_time: 0 }).is_err());
+        assert!(matches!(
+            UnlockPolicy::timelock_in(Duration::from_secs(u64::from(u32::MAX))),
+            Err(PurityError::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use iota_sdk::types::block::{
+    address::{Bech32Address, Hrp, ToBech32Ext},
+    output::{unlock_condition::UnlockConditions, Output, OutputId, OutputWithMetadata},
+    BlockId,
+};
+
+use crate::encryption::DecryptionKey;
+use crate::envelope::{self, Opened};
+use crate::error::{PurityError, Result};
+use crate::signature::Publisher;
+
+/// A data output decoded from the ledger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PurityRecord {
+    pub output_id: OutputId,
+    pub tag: Vec<u8>,
+    /// Data carried by the output, reassembled when the output is the manifest of a fragmented payload.
+    ///
+    /// Sealed data is the encryption envelope, see [`is_encrypted`](Self::is_encrypted).
+    pub metadata: Vec<u8>,
+    /// Address in the `SenderFeature`, if the writer set one.
+    pub sender: Option<Bech32Address>,
+    pub unlock_conditions: UnlockConditions,
+    /// Block that booked the output.
+    pub block_id: BlockId,
+    /// Unix timestamp (seconds) of the milestone that booked the output.
+    pub milestone_timestamp: u32,
+    pub is_spent: bool,
+    /// Signer of the data, when it was written signed. Encrypted data is only checked once decrypted.
+    pub publisher: Option<Publisher>,
+    sealed: bool,
+}
+
+impl PurityRecord {
+    /// Decodes a basic data output, `hrp` is used to render the sender address.
+    ///
+    /// The metadata is taken as is: manifests are not resolved and signatures are not checked here,
+    /// see `client::read_records`.
+    pub fn from_output(output: &OutputWithMetadata, hrp: Hrp) -> Result<Self> {
+        let metadata = output.metadata();
+        let basic = match output.output() {
+            Output::Basic(b) => b,
+            _ => return Err(PurityError::InvalidData(format!("output {} is not a basic output", metadata.output_id()))),
+        };
+        let features = basic.features();
+
+        Ok(Self {
+            output_id: *metadata.output_id(),
+            tag: features.tag().map(|t| t.tag().to_vec()).unwrap_or_default(),
+            metadata: features.metadata().map(|m| m.data().to_vec()).unwrap_or_default(),
+            sender: features.sender().map(|s| (*s.address()).to_bech32(hrp)),
+            unlock_conditions: basic.unlock_conditions().clone(),
+            block_id: *metadata.block_id(),
+            milestone_timestamp: metadata.milestone_timestamp_booked(),
+            is_spent: metadata.is_spent(),
+            publisher: None,
+            sealed: false,
+        })
+    }
+
+    /// The tag as text, when it is valid UTF-8.
+    pub fn tag_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.tag).ok()
+    }
+
+    /// Whether the data is still sealed, see [`encryption`](crate::encryption).
+    pub fn is_encrypted(&self) -> bool {
+        self.sealed
+    }
+
+    /// Opens the sealed data in place with `key`, then checks the signature it may carry.
+    pub async fn decrypt(&mut self, key: &dyn DecryptionKey) -> Result<()> {
+        if !self.sealed {
+            return Err(PurityError::InvalidInput(format!("record {} is not encrypted", self.output_id)));
+        }
+        let (data, publisher) = envelope::unseal(&self.tag, &self.metadata, key).await?;
+        self.metadata = data;
+        self.publisher = publisher;
+        self.sealed = false;
+        Ok(())
+    }
+
+    /// Whether the data was signed by `public_key` and the signature is valid.
+    pub fn is_authentic_from(&self, public_key: &[u8; 32]) -> bool {
+        self.publisher.is_some_and(|p| p.is_authentic_from(public_key))
+    }
+
+    /// Strips the layers of the payload, recording the publisher, see [`envelope::open`].
+    ///
+    /// A payload that can't be opened is left as is.
+    pub(crate) fn open_payload(&mut self) {
+        match envelope::open(&self.tag, self.metadata.clone()) {
+            Ok(Opened::Plain { data, publisher }) => {
+                self.metadata = data;
+                self.publisher = publisher;
+            }
+            Ok(Opened::Sealed(envelope)) => {
+                self.metadata = envelope;
+                self.sealed = true;
+            }
+            Err(e) => log::warn!("record {} left undecoded: {e}", self.output_id),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! One setup API for the secret managers Purity can sign with.
+//!
+//! A [`SecretSource`] names where the keys come from: a Stronghold snapshot, a mnemonic or a
+//! hex seed. [`create_secret_manager`] turns it into the `SecretManager` taken by the wallet
+//! and client helpers. Mnemonics and seeds kept in plain text are meant for development only.
+
+use std::path::{Path, PathBuf};
+
+use iota_sdk::client::{
+    secret::{stronghold::StrongholdSecretManager, SecretManager},
+    stronghold::{Error as StrongholdError, StrongholdAdapter},
+    utils::verify_mnemonic,
+    Client,
+};
+use iota_sdk::crypto::keys::bip39::Mnemonic;
+
+use crate::config::PurityConfig;
+use crate::error::{PurityError, Result};
+
+/// How a Stronghold snapshot is set up.
+#[derive(Clone)]
+pub enum StrongholdInit {
+    /// Open an existing snapshot.
+    Open,
+    /// Create a new snapshot holding a newly generated mnemonic, the snapshot is its only copy.
+    Create,
+    /// Create a new snapshot holding this mnemonic.
+    Import(String),
+}
+
+/// Where the signing keys come from.
+#[derive(Clone)]
+pub enum SecretSource {
+    Stronghold {
+        snapshot_path: PathBuf,
+        password: String,
+        init: StrongholdInit,
+    },
+    /// A BIP-39 mnemonic, in plain text.
+    Mnemonic(String),
+    /// A 32 bytes seed in hex, with or without the `0x` prefix, in plain text.
+    HexSeed(String),
+}
+
+impl SecretSource {
+    /// Picks the source set up in `config`.
+    ///
+    /// A mnemonic and a seed set together are an error, the keys would depend on which one wins.
+    /// A Stronghold snapshot path takes precedence: an existing snapshot is opened, a missing one
+    /// is created holding the configured mnemonic, or a new one. Otherwise the mnemonic or the
+    /// seed is used.
+    pub fn from_config(config: &PurityConfig) -> Result<Self> {
+        if config.mnemonic.is_some() && config.seed.is_some() {
+            return Err(PurityError::InvalidInput("both a mnemonic and a seed are configured, set only one".to_string()));
+        }
+        if let Some(snapshot_path) = &config.stronghold_snapshot_path {
+            let init = if snapshot_path.exists() {
+                StrongholdInit::Open
+            } else {
+                config.mnemonic.clone().map_or(StrongholdInit::Create, StrongholdInit::Import)
+            };
+            return Ok(Self::Stronghold {
+                snapshot_path: snapshot_path.clone(),
+                password: config.stronghold_password()?.to_owned(),
+                init,
+            });
+        }
+        if let Some(mnemonic) = &config.mnemonic {
+            return Ok(Self::Mnemonic(mnemonic.clone()));
+        }
+        if let Some(seed) = &config.seed {
+            return Ok(Self::HexSeed(seed.clone()));
+        }
+        Err(PurityError::MissingConfig("stronghold_snapshot_path, mnemonic or seed"))
+    }
+
+    pub fn is_stronghold(&self) -> bool {
+        matches!(self, Self::Stronghold { .. })
+    }
+}
+
+// Secrets are never printed
+impl std::fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stronghold { snapshot_path, init, .. } => {
+                let init = match init {
+                    StrongholdInit::Open => "open",
+                    StrongholdInit::Create => "create",
+                    StrongholdInit::Import(_) => "import",
+                };
+                f.debug_struct("Stronghold")
+                    .field("snapshot_path", snapshot_path)
+                    .field("init", &init)
+                    .finish_non_exhaustive()
+            }
+            Self::Mnemonic(_) => f.write_str("Mnemonic(<redacted>)"),
+            Self::HexSeed(_) => f.write_str("HexSeed(<redacted>)"),
+        }
+    }
+}
+
+/// Sets up the secret manager of `source`.
+pub async fn create_secret_manager(source: SecretSource) -> Result<SecretManager> {
+    match source {
+        SecretSource::Stronghold { snapshot_path, password, init } => {
+            Ok(SecretManager::Stronghold(open_stronghold(&snapshot_path, password, init).await?))
+        }
+        SecretSource::Mnemonic(mnemonic) => {
+            let mnemonic = checked_mnemonic(mnemonic)?;
+            Ok(SecretManager::try_from_mnemonic(mnemonic)?)
+        }
+        SecretSource::HexSeed(seed) => {
+            let hex = seed.strip_prefix("0x").unwrap_or(&seed);
+            if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(PurityError::InvalidSecret("the seed must be 32 bytes in hex".to_string()));
+            }
+            Ok(SecretManager::try_from_hex_seed(format!("0x{hex}"))?)
+        }
+    }
+}
+
+/// Opens or creates the Stronghold snapshot at `snapshot_path`, as requested by `init`.
+pub async fn open_stronghold(snapshot_path: &Path, password: String, init: StrongholdInit) -> Result<StrongholdAdapter> {
+    let exists = snapshot_path.exists();
+    match (&init, exists) {
+        (StrongholdInit::Open, false) => {
+            return Err(PurityError::NotFound(format!("Stronghold snapshot {}", snapshot_path.display())));
+        }
+        (StrongholdInit::Create | StrongholdInit::Import(_), true) => {
+            return Err(PurityError::InvalidSecret(format!(
+                "Stronghold snapshot {} already exists", snapshot_path.display()
+            )));
+        }
+        _ => {}
+    }
+    // The mnemonic is checked before a snapshot is written
+    let mnemonic = match init {
+        StrongholdInit::Open => None,
+        StrongholdInit::Create => Some(Client::generate_mnemonic()?),
+        StrongholdInit::Import(mnemonic) => Some(checked_mnemonic(mnemonic)?),
+    };
+
+    let secret_manager = StrongholdSecretManager::builder()
+        .password(password)
+        .build(snapshot_path)
+        .map_err(|e| match e {
+            StrongholdError::InvalidPassword => {
+                PurityError::InvalidSecret(format!("wrong password for Stronghold snapshot {}", snapshot_path.display()))
+            }
+            e => e.into(),
+        })?;
+
+    if let Some(mnemonic) = mnemonic {
+        log::info!("Storing mnemonic...");
+        secret_manager.store_mnemonic(mnemonic).await?;
+    }
+    Ok(secret_manager)
+}
+
+fn checked_mnemonic(mnemonic: String) -> Result<Mnemonic> {
+    let mnemonic = Mnemonic::from(mnemonic.trim().to_owned());
+    verify_mnemonic(&*mnemonic).map_err(|e| PurityError::InvalidSecret(e.to_string()))?;
+    Ok(mnemonic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PurityConfigBuilder;
+
+    fn config() -> PurityConfigBuilder {
+        PurityConfig::builder().with_node_url("http://node")
+    }
+
+    #[test]
+    fn source_follows_the_config() {
+        let source = |config: PurityConfigBuilder| SecretSource::from_config(&config.finish().unwrap());
+        assert!(matches!(source(config().with_mnemonic("words")), Ok(SecretSource::Mnemonic(m)) if m == "words"));
+        assert!(matches!(source(config().with_seed("0x01")), Ok(SecretSource::HexSeed(s)) if s == "0x01"));
+        assert!(matches!(source(config()), Err(PurityError::MissingConfig(_))));
+        assert!(matches!(source(config().with_mnemonic("words").with_seed("0x01")), Err(PurityError::InvalidInput(_))));
+
+        let stronghold = config()
+            .with_stronghold_snapshot_path("missing.stronghold")
+            .with_stronghold_password("password")
+            .with_mnemonic("words");
+        let Ok(SecretSource::Stronghold { init: StrongholdInit::Import(mnemonic), .. }) = source(stronghold) else {
+            panic!("expected a Stronghold snapshot importing the mnemonic");
+        };
+        assert_eq!(mnemonic, "words");
+    }
+
+    #[tokio::test]
+    async fn plain_text_secrets_are_checked() {
+        let seed = hex::encode([5; 32]);
+        assert!(create_secret_manager(SecretSource::HexSeed(seed.clone())).await.is_ok());
+        assert!(create_secret_manager(SecretSource::HexSeed(format!("0x{seed}"))).await.is_ok());
+        for seed in ["0x00".to_string(), "zz".repeat(32)] {
+            assert!(matches!(create_secret_manager(SecretSource::HexSeed(seed)).await, Err(PurityError::InvalidSecret(_))));
+        }
+
+        let mnemonic = Client::generate_mnemonic().unwrap().to_string();
+        assert!(create_secret_manager(SecretSource::Mnemonic(format!(" {mnemonic}\n"))).await.is_ok());
+        let invalid = SecretSource::Mnemonic("not a valid mnemonic".to_string());
+        assert!(matches!(create_secret_manager(invalid).await, Err(PurityError::InvalidSecret(_))));
+    }
+
+    #[test]
+    fn secrets_are_not_printed() {
+        let seed = hex::encode([5; 32]);
+        assert_eq!(format!("{:?}", SecretSource::HexSeed(seed)), "HexSeed(<redacted>)");
+        assert_eq!(format!("{:?}", SecretSource::Mnemonic("words".to_string())), "Mnemonic(<redacted>)");
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Publisher signatures on data payloads.
+//!
+//! The payload is signed with an Ed25519 key derived by the secret manager and wrapped as
+//! `magic | public key (32) | signature (64) | payload`. The signed message also covers the
+//! tag, so a signed payload can't be replayed under another tag.
+
+use iota_sdk::client::secret::{SecretManage, SecretManager};
+use iota_sdk::crypto::keys::bip44::Bip44;
+use iota_sdk::crypto::signatures::ed25519::{PublicKey, PublicKeyBytes, Signature};
+
+use crate::error::{PurityError, Result};
+
+/// Magic bytes at the start of every signed payload.
+pub const SIGNED_MAGIC: [u8; 4] = *b"PSG1";
+/// Bytes added by the signature to the payload.
+pub const SIGNATURE_OVERHEAD: usize = SIGNED_MAGIC.len() + PublicKey::LENGTH + Signature::LENGTH;
+// Domain separation of the signed message
+const SIGNATURE_DOMAIN: &[u8] = b"purity-sig-v1";
+
+/// Author of a signed payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Publisher {
+    /// Ed25519 public key that signed the payload.
+    pub public_key: [u8; PublicKey::LENGTH],
+    /// Whether the signature matches the payload and its tag.
+    pub verified: bool,
+}
+
+impl Publisher {
+    /// Whether the payload was signed by `public_key` and the signature is valid.
+    pub fn is_authentic_from(&self, public_key: &[u8; PublicKey::LENGTH]) -> bool {
+        self.verified && &self.public_key == public_key
+    }
+}
+
+/// Whether `data` is a signed payload.
+pub fn is_signed(data: &[u8]) -> bool {
+    data.len() >= SIGNATURE_OVERHEAD && data[..SIGNED_MAGIC.len()] == SIGNED_MAGIC
+}
+
+/// Signs `payload`, written under `tag`, with the key at `chain` and wraps it in an envelope.
+pub async fn sign(secret_manager: &SecretManager, chain: Bip44, tag: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let signature = secret_manager.sign_ed25519(&signing_message(tag, payload), chain).await?;
+
+    let mut envelope = Vec::with_capacity(SIGNATURE_OVERHEAD + payload.len());
+    envelope.extend_from_slice(&SIGNED_MAGIC);
+    envelope.extend_from_slice(signature.public_key_bytes().as_slice());
+    envelope.extend_from_slice(&signature.signature().to_bytes());
+    envelope.extend_from_slice(payload);
+    Ok(envelope)
+}
+
+/// Public key used by [`sign`] with the same `chain`, to be shared with the readers.
+pub async fn publisher_key(secret_manager: &SecretManager, chain: Bip44) -> Result<[u8; PublicKey::LENGTH]> {
+    // The secret manager only exposes the key through a signature
+    let signature = secret_manager.sign_ed25519(SIGNATURE_DOMAIN, chain).await?;
+    Ok(signature.public_key_bytes().to_bytes())
+}
+
+/// Splits a signed payload written under `tag` and checks its signature.
+///
+/// A signature that doesn't match gives an unverified publisher, a malformed envelope an error.
+pub fn open(tag: &[u8], data: &[u8]) -> Result<(Publisher, Vec<u8>)> {
+    if !is_signed(data) {
+        return Err(PurityError::InvalidData("payload is not signed".to_string()));
+    }
+    let (key, rest) = data[SIGNED_MAGIC.len()..].split_at(PublicKey::LENGTH);
+    let (signature, payload) = rest.split_at(Signature::LENGTH);
+    let public_key: [u8; PublicKey::LENGTH] = key.try_into().expect("length checked by is_signed");
+    let signature = Signature::from_bytes(signature.try_into().expect("length checked by is_signed"));
+
+    let verified = PublicKeyBytes::from_bytes(public_key)
+        .verify(&signature, &signing_message(tag, payload))
+        .unwrap_or(false);
+    Ok((Publisher { public_key, verified }, payload.to_vec()))
+}
+
+fn signing_message(tag: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNATURE_DOMAIN.len() + 1 + tag.len() + payload.len());
+    message.extend_from_slice(SIGNATURE_DOMAIN);
+    // Tags are at most 64 bytes
+    message.push(tag.len() as u8);
+    message.extend_from_slice(tag);
+    message.extend_from_slice(payload);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::client::constants::SHIMMER_COIN_TYPE;
+
+    use super::*;
+    use crate::secret::{create_secret_manager, SecretSource};
+
+    async fn secret_manager() -> SecretManager {
+        create_secret_manager(SecretSource::HexSeed(hex::encode([3; 32]))).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn signed_payload_is_verified_under_its_tag() {
+        let secret_manager = secret_manager().await;
+        let chain = Bip44::new(SHIMMER_COIN_TYPE);
+        let signed = sign(&secret_manager, chain, b"tag", b"payload").await.unwrap();
+        assert!(is_signed(&signed));
+        assert_eq!(signed.len(), SIGNATURE_OVERHEAD + b"payload".len());
+
+        let key = publisher_key(&secret_manager, chain).await.unwrap();
+        let (publisher, payload) = open(b"tag", &signed).unwrap();
+        assert!(publisher.is_authentic_from(&key));
+        assert_eq!(payload, b"payload");
+
+        // Moving the payload to another tag breaks the signature
+        let (publisher, _) = open(b"other", &signed).unwrap();
+        assert!(!publisher.verified);
+    }
+
+    #[tokio::test]
+    async fn altered_payload_is_not_verified() {
+        let secret_manager = secret_manager().await;
+        let mut signed = sign(&secret_manager, Bip44::new(SHIMMER_COIN_TYPE), b"tag", b"payload").await.unwrap();
+        *signed.last_mut().unwrap() ^= 1;
+        let (publisher, _) = open(b"tag", &signed).unwrap();
+        assert!(!publisher.verified);
+
+        assert!(open(b"tag", b"payload").is_err());
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local index of the data written by this application.
+//!
+//! Records are kept in RocksDB under these key families:
+//! - `record/{output_id}`: the JSON encoded [`WriteRecord`]
+//! - `tag/{hex tag}/{timestamp}/{output_id}`: index by tag, ordered by time
+//! - `time/{timestamp}/{output_id}`: index by time
+//! - `archive/{output_id}`: the JSON encoded [`ArchivedOutput`] of a swept data output
+//!
+//! Timestamps are zero padded so that the lexicographic order of the keys is the time order.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+
+use iota_sdk::types::block::{output::OutputId, payload::transaction::TransactionId, BlockId};
+
+use crate::error::{PurityError, Result};
+use crate::fragment::payload_hash;
+use crate::writer::WriteReceipt;
+
+const RECORD_PREFIX: &str = "record/";
+const TAG_PREFIX: &str = "tag/";
+const TIME_PREFIX: &str = "time/";
+const ARCHIVE_PREFIX: &str = "archive/";
+
+/// A write performed by this application.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteRecord {
+    pub tag: String,
+    pub output_id: OutputId,
+    pub block_id: Option<BlockId>,
+    pub transaction_id: TransactionId,
+    /// Blake2b-256 of the payload, hex encoded.
+    pub payload_hash: String,
+    /// Payload size in bytes.
+    pub size: usize,
+    /// Unix time of the write in milliseconds.
+    pub timestamp: u64,
+}
+
+impl WriteRecord {
+    pub fn new(tag: &str, output_id: OutputId, block_id: Option<BlockId>, payload: &[u8]) -> Self {
+        Self {
+            tag: tag.to_string(),
+            output_id,
+            block_id,
+            transaction_id: *output_id.transaction_id(),
+            payload_hash: hex::encode(payload_hash(payload)),
+            size: payload.len(),
+            timestamp: unix_millis(),
+        }
+    }
+
+    /// Builds the record of a write from its receipt.
+    pub fn from_receipt(tag: &str, receipt: &WriteReceipt, payload: &[u8]) -> Self {
+        Self::new(tag, receipt.output_id(), Some(receipt.block_id), payload)
+    }
+}
+
+/// Content of a data output saved before the output was consumed to reclaim its deposit.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedOutput {
+    pub output_id: OutputId,
+    #[serde(with = "hex")]
+    pub tag: Vec<u8>,
+    /// Data as written on the ledger, reassembled if the output was a manifest.
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
+    /// Storage deposit returned to the wallet.
+    pub amount: u64,
+    /// Unix time of the archival in milliseconds.
+    pub timestamp: u64,
+}
+
+impl ArchivedOutput {
+    pub fn new(output_id: OutputId, tag: Vec<u8>, data: Vec<u8>, amount: u64) -> Self {
+        Self {
+            output_id,
+            tag,
+            data,
+            amount,
+            timestamp: unix_millis(),
+        }
+    }
+}
+
+/// Persistent store of [`WriteRecord`]s, queryable by tag and by time range.
+#[derive(Debug)]
+pub struct PurityStorage {
+    db: DB,
+}
+
+impl PurityStorage {
+    /// Opens the store at `path`, creating it if missing.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db = DB::open_default(path)?;
+        Ok(Self { db })
+    }
+
+    /// Stores `record`, replacing the one with the same output id and its index keys.
+    pub fn insert(&self, record: &WriteRecord) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        if let Some(previous) = self.get(&record.output_id)? {
+            batch.delete(tag_key(&previous.tag, previous.timestamp, &previous.output_id));
+            batch.delete(time_key(previous.timestamp, &previous.output_id));
+        }
+        batch.put(record_key(&record.output_id), serde_json::to_vec(record).map_err(json_error)?);
+        batch.put(tag_key(&record.tag, record.timestamp, &record.output_id), []);
+        batch.put(time_key(record.timestamp, &record.output_id), []);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    pub fn get(&self, output_id: &OutputId) -> Result<Option<WriteRecord>> {
+        match self.db.get(record_key(output_id))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes).map_err(json_error)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn archive(&self, archived: &ArchivedOutput) -> Result<()> {
+        self.db.put(archive_key(&archived.output_id), serde_json::to_vec(archived).map_err(json_error)?)?;
+        Ok(())
+    }
+
+    pub fn archived(&self, output_id: &OutputId) -> Result<Option<ArchivedOutput>> {
+        match self.db.get(archive_key(output_id))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes).map_err(json_error)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Records written under `tag`, oldest first.
+    pub fn records_by_tag(&self, tag: &str) -> Result<Vec<WriteRecord>> {
+        let prefix = format!("{TAG_PREFIX}{}/", hex::encode(tag));
+        self.collect(&prefix, &format!("{prefix}~"))
+    }
+
+    /// Records written in the `[from, to)` interval of unix milliseconds, oldest first.
+    pub fn records_between(&self, from: u64, to: u64) -> Result<Vec<WriteRecord>> {
+        self.collect(&format!("{TIME_PREFIX}{from:020}/"), &format!("{TIME_PREFIX}{to:020}/"))
+    }
+
+    // Resolves the records referenced by the index keys in `[start, end)`
+    fn collect(&self, start: &str, end: &str) -> Result<Vec<WriteRecord>> {
+        let mut records = Vec::new();
+        for item in self.db.iterator(IteratorMode::From(start.as_bytes(), Direction::Forward)) {
+            let (key, _) = item?;
+            if &*key >= end.as_bytes() {
+                break;
+            }
+            let output_id = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|key| key.rsplit('/').next())
+                .and_then(|id| id.parse::<OutputId>().ok())
+                .ok_or_else(|| PurityError::InvalidData("malformed storage index key".to_string()))?;
+            records.push(self.get(&output_id)?.ok_or_else(|| PurityError::NotFound(format!("record {output_id}")))?);
+        }
+        Ok(records)
+    }
+}
+
+/// Records a confirmed write in `storage`, if given; a write that is not confirmed may still be dropped.
+pub(crate) fn record_write(storage: Option<&PurityStorage>, tag: &str, receipt: &WriteReceipt, payload: &[u8]) -> Result<()> {
+    match storage {
+        Some(storage) if receipt.inclusion.is_confirmed() => storage.insert(&WriteRecord::from_receipt(tag, receipt, payload)),
+        Some(_) => {
+            log::debug!("write {} not stored, its block is {}", receipt.output_id(), receipt.inclusion);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+// Records that can't be encoded or decoded are a failure of the store
+fn json_error(error: serde_json::Error) -> PurityError {
+    PurityError::Storage(Box::new(error))
+}
+
+fn record_key(output_id: &OutputId) -> String {
+    format!("{RECORD_PREFIX}{output_id}")
+}
+
+fn tag_key(tag: &str, timestamp: u64, output_id: &OutputId) -> String {
+    format!("{TAG_PREFIX}{}/{timestamp:020}/{output_id}", hex::encode(tag))
+}
+
+fn time_key(timestamp: u64, output_id: &OutputId) -> String {
+    format!("{TIME_PREFIX}{timestamp:020}/{output_id}")
+}
+
+fn archive_key(output_id: &OutputId) -> String {
+    format!("{ARCHIVE_PREFIX}{output_id}")
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock went backwards")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::confirmation::InclusionState;
+    use crate::timing::WriteTimings;
+
+    // Store in a directory of its own, removed when dropped
+    struct TempStorage(PurityStorage, PathBuf);
+
+    impl TempStorage {
+        fn open(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("purity-storage-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(PurityStorage::open(&path).unwrap(), path)
+        }
+    }
+
+    impl Drop for TempStorage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.1);
+        }
+    }
+
+    fn record(tag: &str, index: u16, timestamp: u64) -> WriteRecord {
+        let output_id = OutputId::new(TransactionId::new([index as u8; 32]), index).unwrap();
+        WriteRecord {
+            timestamp,
+            ..WriteRecord::new(tag, output_id, None, b"payload")
+        }
+    }
+
+    fn receipt(output_id: OutputId, inclusion: InclusionState) -> WriteReceipt {
+        WriteReceipt {
+            transaction_id: *output_id.transaction_id(),
+            block_id: BlockId::new([9; 32]),
+            output_ids: vec![output_id],
+            storage_deposit: 0,
+            payload_len: 7,
+            encoded_len: 13,
+            inclusion,
+            timings: WriteTimings::default(),
+            explorer_url: None,
+            alias_id: None,
+        }
+    }
+
+    #[test]
+    fn records_are_indexed_by_tag_and_time() {
+        let storage = TempStorage::open("index");
+        let (first, second, other) = (record("t", 1, 100), record("t", 2, 200), record("u", 3, 150));
+        for record in [&second, &other, &first] {
+            storage.0.insert(record).unwrap();
+        }
+
+        assert_eq!(storage.0.get(&first.output_id).unwrap(), Some(first.clone()));
+        assert_eq!(storage.0.records_by_tag("t").unwrap(), [first.clone(), second.clone()]);
+        assert_eq!(storage.0.records_between(100, 200).unwrap(), [first, other.clone()]);
+        assert_eq!(storage.0.records_by_tag("u").unwrap(), [other]);
+    }
+
+    #[test]
+    fn reinsert_drops_the_previous_index_keys() {
+        let storage = TempStorage::open("reinsert");
+        let record = record("t", 1, 100);
+        storage.0.insert(&record).unwrap();
+        let moved = WriteRecord {
+            tag: "u".to_string(),
+            timestamp: 300,
+            ..record.clone()
+        };
+        storage.0.insert(&moved).unwrap();
+
+        assert!(storage.0.records_by_tag("t").unwrap().is_empty());
+        assert!(storage.0.records_between(0, 200).unwrap().is_empty());
+        assert_eq!(storage.0.records_between(0, 400).unwrap(), std::slice::from_ref(&moved));
+        assert_eq!(storage.0.records_by_tag("u").unwrap(), [moved]);
+    }
+
+    #[test]
+    fn only_confirmed_writes_are_recorded() {
+        let storage = TempStorage::open("confirmed");
+        let (pending, confirmed) = (record("t", 1, 0).output_id, record("t", 2, 0).output_id);
+        record_write(Some(&storage.0), "t", &receipt(pending, InclusionState::Solid), b"payload").unwrap();
+        let state = InclusionState::Confirmed { milestone_index: 1 };
+        record_write(Some(&storage.0), "t", &receipt(confirmed, state), b"payload").unwrap();
+
+        assert_eq!(storage.0.get(&pending).unwrap(), None);
+        let stored = storage.0.get(&confirmed).unwrap().unwrap();
+        assert_eq!((stored.block_id, stored.size), (Some(BlockId::new([9; 32])), 7));
+        assert_eq!(stored.payload_hash, hex::encode(payload_hash(b"payload")));
+    }
+
+    #[test]
+    fn archived_outputs_are_kept_by_id() {
+        let storage = TempStorage::open("archive");
+        let archived = ArchivedOutput::new(record("t", 1, 0).output_id, b"t".to_vec(), b"data".to_vec(), 50_000);
+        storage.0.archive(&archived).unwrap();
+        // Archiving again is harmless
+        storage.0.archive(&archived).unwrap();
+        assert_eq!(storage.0.archived(&archived.output_id).unwrap(), Some(archived));
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Latency of the phases of a write.
+//!
+//! Every phase runs inside a `tracing` span named after it, so any subscriber can record its
+//! duration, and the write returns the totals in a [`WriteTimings`]. Nothing is printed.
+
+use std::future::Future;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Instrument, Span};
+
+/// A phase of a write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Building the data outputs, storage deposit included.
+    OutputBuild,
+    /// Input selection, transaction essence and, where it is a separate step, signing.
+    BlockBuild,
+    /// Posting the block; the wallet signs in this step too.
+    Submission,
+    /// Waiting for the block to be included in the ledger.
+    InclusionWait,
+    /// Syncing the wallet after the write.
+    Sync,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::OutputBuild => "output_build",
+            Self::BlockBuild => "block_build",
+            Self::Submission => "submission",
+            Self::InclusionWait => "inclusion_wait",
+            Self::Sync => "sync",
+        }
+    }
+
+    fn span(self) -> Span {
+        match self {
+            Self::OutputBuild => info_span!(target: "purity::timing", "output_build"),
+            Self::BlockBuild => info_span!(target: "purity::timing", "block_build"),
+            Self::Submission => info_span!(target: "purity::timing", "submission"),
+            Self::InclusionWait => info_span!(target: "purity::timing", "inclusion_wait"),
+            Self::Sync => info_span!(target: "purity::timing", "sync"),
+        }
+    }
+}
+
+/// Time spent in each phase of a write, summed over all of its transactions.
+///
+/// Serialized in whole milliseconds, with the field names of the timing event.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteTimings {
+    #[serde(rename = "output_build_ms", with = "millis")]
+    pub output_build: Duration,
+    #[serde(rename = "block_build_ms", with = "millis")]
+    pub block_build: Duration,
+    #[serde(rename = "submission_ms", with = "millis")]
+    pub submission: Duration,
+    #[serde(rename = "inclusion_wait_ms", with = "millis")]
+    pub inclusion_wait: Duration,
+    #[serde(rename = "sync_ms", with = "millis")]
+    pub sync: Duration,
+}
+
+impl WriteTimings {
+    pub fn get(&self, phase: Phase) -> Duration {
+        match phase {
+            Phase::OutputBuild => self.output_build,
+            Phase::BlockBuild => self.block_build,
+            Phase::Submission => self.submission,
+            Phase::InclusionWait => self.inclusion_wait,
+            Phase::Sync => self.sync,
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.output_build + self.block_build + self.submission + self.inclusion_wait + self.sync
+    }
+
+    pub(crate) fn add(&mut self, phase: Phase, elapsed: Duration) {
+        match phase {
+            Phase::OutputBuild => self.output_build += elapsed,
+            Phase::BlockBuild => self.block_build += elapsed,
+            Phase::Submission => self.submission += elapsed,
+            Phase::InclusionWait => self.inclusion_wait += elapsed,
+            Phase::Sync => self.sync += elapsed,
+        }
+    }
+
+    /// Runs `f` in the span of `phase`, adding its duration.
+    pub(crate) fn measure<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let value = phase.span().in_scope(f);
+        self.add(phase, start.elapsed());
+        value
+    }
+
+    /// Awaits `future` in the span of `phase`, adding its duration.
+    pub(crate) async fn measure_async<F: Future>(&mut self, phase: Phase, future: F) -> F::Output {
+        let start = Instant::now();
+        let value = future.instrument(phase.span()).await;
+        self.add(phase, start.elapsed());
+        value
+    }
+
+    /// Emits the totals as a `tracing` event of the current span.
+    pub(crate) fn emit(&self) {
+        tracing::info!(
+            output_build_ms = self.output_build.as_millis() as u64,
+            block_build_ms = self.block_build.as_millis() as u64,
+            submission_ms = self.submission.as_millis() as u64,
+            inclusion_wait_ms = self.inclusion_wait.as_millis() as u64,
+            sync_ms = self.sync.as_millis() as u64,
+            total_ms = self.total().as_millis() as u64,
+            "write timings"
+        );
+    }
+}
+
+impl AddAssign for WriteTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.output_build += other.output_build;
+        self.block_build += other.block_build;
+        self.submission += other.submission;
+        self.inclusion_wait += other.inclusion_wait;
+        self.sync += other.sync;
+    }
+}
+
+mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
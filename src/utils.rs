@@ -12,54 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
-use anyhow::Context;
 use iota_sdk::client::Client;
 use iota_sdk::client::node_api::indexer::query_parameters::QueryParameter;
-use iota_sdk::crypto::keys::bip39::Mnemonic;
-use iota_sdk::types::api::core::response::OutputWithMetadataResponse;
 use iota_sdk::types::block::address::Bech32Address;
-use iota_sdk::types::block::output::{OutputWithMetadata, Output};
-use iota_sdk::types::block::output::dto::OutputDto;
-use iota_sdk::types::block::output::feature::dto::FeatureDto;
+use iota_sdk::types::block::output::Output;
 
 use iota_sdk::client::stronghold::StrongholdAdapter;
 use iota_sdk::client::constants::SHIMMER_COIN_TYPE;
-use iota_sdk::client::secret::{stronghold::StrongholdSecretManager, SecretManager};
+use iota_sdk::client::secret::SecretManager;
 
 use iota_sdk::Wallet;
-use iota_sdk::wallet::{ClientOptions, Result, Account};
+use iota_sdk::wallet::{ClientOptions, Account};
 
-pub async fn setup_secret_manager() -> Result<StrongholdAdapter> {
+use crate::config::PurityConfig;
+use crate::error::{PurityError, Result};
+use crate::secret::{create_secret_manager, open_stronghold, SecretSource, StrongholdInit};
 
-    let exists = PathBuf::from(&std::env::var("STRONGHOLD_SNAPSHOT_PATH").unwrap()).exists();
-
-    // Setup Stronghold secret_manager
-    let secret_manager = StrongholdSecretManager::builder()
-        .password(std::env::var("STRONGHOLD_PASSWORD").unwrap())
-        .build(std::env::var("STRONGHOLD_SNAPSHOT_PATH").unwrap())?;
+/// Reads an environment variable, failing with [`PurityError::MissingEnvVar`] if it is not set.
+pub fn env_var(name: &str) -> Result<String> {
+    std::env::var(name).map_err(|e| PurityError::MissingEnvVar(name.to_string(), e))
+}
 
-    if !exists {
-        log::info!("Storing mnemonic...");
-        // Only required the first time, can also be generated with `manager.generate_mnemonic()?`
-        let mnemonic = Mnemonic::from(std::env::var("MNEMONIC").unwrap());
+/// Opens the Stronghold snapshot of the configuration, creating it with the configured mnemonic the first time.
+pub async fn setup_secret_manager(config: &PurityConfig) -> Result<StrongholdAdapter> {
 
-        // The mnemonic only needs to be stored the first time
-        secret_manager.store_mnemonic(mnemonic).await?;
-    }
-
-    Ok(secret_manager)
+    let snapshot_path = config.stronghold_snapshot_path()?;
+    let init = if snapshot_path.exists() {
+        StrongholdInit::Open
+    } else {
+        StrongholdInit::Import(config.mnemonic()?.to_owned())
+    };
+    open_stronghold(snapshot_path, config.stronghold_password()?.to_owned(), init).await
 }
 
-pub async fn setup_wallet(secret_manager: StrongholdAdapter) -> Result<Wallet> {
+/// Creates the wallet with any secret manager, see [`crate::secret`].
+pub async fn setup_wallet(config: &PurityConfig, secret_manager: SecretManager) -> Result<Wallet> {
 
     // Create the wallet with the secret_manager and client options
-    let client_options = ClientOptions::new().with_node(&std::env::var("NODE_URL").unwrap())?;
+    let client_options = ClientOptions::new().with_node(&config.node_url)?;
 
     // Create the wallet
     let wallet = Wallet::builder()
-        .with_secret_manager(SecretManager::Stronghold(secret_manager))
-        .with_storage_path(&std::env::var("WALLET_DB_PATH").unwrap())
+        .with_secret_manager(secret_manager)
+        .with_storage_path(config.wallet_db_path()?)
         .with_client_options(client_options)
         .with_coin_type(SHIMMER_COIN_TYPE)
         .finish()
@@ -68,74 +63,74 @@ pub async fn setup_wallet(secret_manager: StrongholdAdapter) -> Result<Wallet> {
     Ok(wallet)
 }
 
-pub async fn create_or_recover_wallet() -> Result<Wallet> {
+/// Opens the wallet database of the configuration, or creates it, with the secret source of the configuration.
+pub async fn create_or_recover_wallet(config: &PurityConfig) -> Result<Wallet> {
 
-    let wallet = if PathBuf::from(&std::env::var("WALLET_DB_PATH").unwrap()).exists() {
+    let wallet_db_path = config.wallet_db_path()?;
+    let source = SecretSource::from_config(config)?;
+    // The database remembers a Stronghold snapshot, other secret managers are given again
+    let wallet = if wallet_db_path.exists() && source.is_stronghold() {
         log::info!("Recovering wallet...");
         let wallet = Wallet::builder()
-        .with_storage_path(&std::env::var("WALLET_DB_PATH").unwrap())
+        .with_storage_path(wallet_db_path)
         .finish()
         .await?;
 
         wallet
-        .set_stronghold_password(std::env::var("STRONGHOLD_PASSWORD").unwrap())
+        .set_stronghold_password(config.stronghold_password()?.to_owned())
         .await?;
         
         Ok(wallet)
     } else {
-        log::info!("Creating wallet...");
-        let secret_manager = setup_secret_manager().await?;
-        setup_wallet(secret_manager).await
+        log::info!("{} wallet...", if wallet_db_path.exists() { "Recovering" } else { "Creating" });
+        let secret_manager = create_secret_manager(source).await?;
+        setup_wallet(config, secret_manager).await
     };
 
     wallet
 }
 
-// pub fn get_metadata(o: OutputWithMetadata) -> anyhow::Result<Vec<u8>> {
-//     match o.into_output() {
-//         Output::Basic(b) => { 
-
-//             for f in b.features().metadata().into_iter() {
-//                 if let FeatureDto::Metadata(m) = f {
-//                     return Ok( hex::decode(&m.data[2..]).unwrap() );               
-//                 }
-//             }
-//             anyhow::bail!("No FeatureDto::Metadata") 
-//         }
-//         _ => { 
-//             anyhow::bail!("No OutputDto of type Basic")
-//         }
-//     }
-// }
+/// Returns the data of the `MetadataFeature` of a basic output.
+pub fn get_metadata(output: &Output) -> Result<Vec<u8>> {
+    match output {
+        Output::Basic(b) => {
+            match b.features().metadata() {
+                Some(m) => Ok(m.data().to_vec()),
+                None => Err(PurityError::InvalidData("No MetadataFeature".to_string())),
+            }
+        }
+        _ => {
+            Err(PurityError::InvalidData("No Output of type Basic".to_string()))
+        }
+    }
+}
 
 // Copyright 2020-2023 IOTA Stiftung
 // SPDX-License-Identifier: Apache-2.0
 
 /// Requests funds from the faucet for the given `address`.
-pub async fn request_faucet_funds(client: &Client, address: &Bech32Address, faucet_endpoint: &str) -> anyhow::Result<()> {
-    iota_sdk::client::request_funds_from_faucet(faucet_endpoint, &address).await?;
+pub async fn request_faucet_funds(client: &Client, address: &Bech32Address, faucet_endpoint: &str) -> Result<()> {
+    iota_sdk::client::request_funds_from_faucet(faucet_endpoint, address).await?;
 
     tokio::time::timeout(std::time::Duration::from_secs(45), async {
         loop {
         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
 
-        let balance = get_address_balance(client, &address)
-            .await
-            .context("failed to get address balance")?;
+        let balance = get_address_balance(client, address).await?;
         if balance > 0 {
             break;
         }
         }
-        Ok::<(), anyhow::Error>(())
+        Ok::<(), PurityError>(())
     })
     .await
-    .context("maximum timeout exceeded")??;
+    .map_err(|_| PurityError::Timeout("faucet funds not received".to_string()))??;
 
     Ok(())
 }
   
 /// Returns the balance of the given Bech32-encoded `address`.
-pub async fn get_address_balance(client: &Client, address: &Bech32Address) -> anyhow::Result<u64> {
+pub async fn get_address_balance(client: &Client, address: &Bech32Address) -> Result<u64> {
     let output_ids = client
         .basic_output_ids(vec![
         QueryParameter::Address(address.to_owned()),
@@ -0,0 +1,226 @@
+// Copyright 2023 Fondazione LINKS
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A single write interface over the wallet and the client-only paths.
+//!
+//! [`PurityWriter`] is implemented by `account::AccountWriter`, which signs with a wallet
+//! `Account`, and by `client::ClientWriter`, which signs with a bare `SecretManager`. Both take
+//! a [`WriteRequest`], fragment large payloads and return a [`WriteReceipt`].
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use iota_sdk::types::block::{
+    address::Bech32Address,
+    output::{
+        feature::{MetadataFeature, SenderFeature, TagFeature},
+        AliasId, BasicOutputBuilder, Feature, Output, OutputId, RentStructure,
+    },
+    payload::transaction::TransactionId,
+    BlockId,
+};
+
+use crate::confirmation::{Confirmation, InclusionState};
+use crate::error::{PurityError, Result};
+use crate::options::WriteOptions;
+use crate::timing::WriteTimings;
+
+/// A payload to write under a tag.
+#[derive(Clone, Debug)]
+pub struct WriteRequest {
+    /// Address unlocking the data output.
+    pub address: Bech32Address,
+    pub tag: String,
+    pub payload: Vec<u8>,
+    pub options: WriteOptions,
+}
+
+impl WriteRequest {
+    pub fn new(address: Bech32Address, tag: impl Into<String>, payload: Vec<u8>, options: impl Into<WriteOptions>) -> Self {
+        Self {
+            address,
+            tag: tag.into(),
+            payload,
+            options: options.into(),
+        }
+    }
+}
+
+/// Outcome of a write.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteReceipt {
+    /// Transaction creating the data output, the manifest of a fragmented payload.
+    pub transaction_id: TransactionId,
+    /// Block holding the transaction, a reattachment of the submitted one if it was replaced.
+    pub block_id: BlockId,
+    /// Data outputs created: the chunks of a fragmented payload, then the output to read.
+    /// For an alias write, the alias output.
+    pub output_ids: Vec<OutputId>,
+    /// Storage deposit locked in the data outputs.
+    pub storage_deposit: u64,
+    /// Payload size as given by the caller.
+    pub payload_len: usize,
+    /// Size written on the ledger, after compression, signature and encryption.
+    pub encoded_len: usize,
+    /// State of the block when the write returned, the payload is stored only once confirmed.
+    pub inclusion: InclusionState,
+    /// Time spent in each phase of the write.
+    pub timings: WriteTimings,
+    /// Block on the explorer of the configuration.
+    pub explorer_url: Option<String>,
+    /// Alias whose state metadata holds the payload, for an alias write.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias_id: Option<AliasId>,
+}
+
+impl WriteReceipt {
+    /// Output to read the payload from.
+    pub fn output_id(&self) -> OutputId {
+        *self.output_ids.last().expect("a write creates at least one output")
+    }
+
+    /// The receipt if its block reached the state `confirmation` waits for, an
+    /// [`PurityError::Unconfirmed`] error otherwise.
+    ///
+    /// Writes return their receipt whatever the state, this turns a shortfall into an error.
+    pub fn confirmed(self, confirmation: &Confirmation) -> Result<Self> {
+        if self.inclusion.satisfies(confirmation) {
+            Ok(self)
+        } else {
+            Err(PurityError::Unconfirmed(self.block_id, self.inclusion))
+        }
+    }
+}
+
+/// Writes payloads to the ledger, whichever way the transactions are signed.
+#[async_trait]
+pub trait PurityWriter: Send + Sync {
+    async fn write(&self, request: WriteRequest) -> Result<WriteReceipt>;
+}
+
+/// Builds a basic output carrying `metadata` under `tag`, as set up by `options`.
+pub(crate) fn data_output(
+    address: &Bech32Address,
+    tag: &str,
+    metadata: Vec<u8>,
+    options: &WriteOptions,
+    rent_structure: RentStructure,
+    token_supply: u64,
+) -> Result<Output> {
+    let builder = BasicOutputBuilder::new_with_minimum_storage_deposit(rent_structure)
+        .add_feature(Feature::Tag(TagFeature::new(tag.as_bytes().to_vec())?));
+    build_output(builder, address, metadata, options, rent_structure, token_supply)
+}
+
+/// Output carrying a chunk of a fragmented payload, see [`fragment`](crate::fragment).
+///
+/// Chunks carry no tag, so readers of the tag only see the manifest.
+pub(crate) fn chunk_output(
+    address: &Bech32Address,
+    chunk: Vec<u8>,
+    options: &WriteOptions,
+    rent_structure: RentStructure,
+    token_supply: u64,
+) -> Result<Output> {
+    let builder = BasicOutputBuilder::new_with_minimum_storage_deposit(rent_structure);
+    build_output(builder, address, chunk, options, rent_structure, token_supply)
+}
+
+fn build_output(
+    builder: BasicOutputBuilder,
+    address: &Bech32Address,
+    metadata: Vec<u8>,
+    options: &WriteOptions,
+    rent_structure: RentStructure,
+    token_supply: u64,
+) -> Result<Output> {
+    let mut builder = builder
+        .add_feature(Feature::Metadata(MetadataFeature::new(metadata)?))
+        .with_unlock_conditions(options.policy.unlock_conditions(address, rent_structure, token_supply)?);
+    if options.sender {
+        builder = builder.add_feature(Feature::Sender(SenderFeature::new(*address)));
+    }
+    Ok(builder.finish_output(token_supply)?)
+}
+
+/// Id of the data output `output` among the outputs created by transaction `transaction_id`.
+pub(crate) fn output_id_of(transaction_id: TransactionId, created: &[Output], output: &Output) -> Result<OutputId> {
+    let mut ids = output_ids_of(transaction_id, created, std::slice::from_ref(output))?;
+    Ok(ids.remove(0))
+}
+
+/// Ids of the data `outputs` among the outputs created by transaction `transaction_id`, in order.
+///
+/// The wallet is free to order the outputs and to add a remainder anywhere, so each output is
+/// found by its features and unlock conditions; equal outputs get distinct indexes.
+pub(crate) fn output_ids_of(transaction_id: TransactionId, created: &[Output], outputs: &[Output]) -> Result<Vec<OutputId>> {
+    let mut used = vec![false; created.len()];
+    outputs
+        .iter()
+        .map(|output| {
+            let index = created
+                .iter()
+                .enumerate()
+                .position(|(i, o)| !used[i] && same_data_output(o, output))
+                .ok_or_else(|| PurityError::NotFound(format!("data output in transaction {transaction_id}")))?;
+            used[index] = true;
+            Ok(OutputId::new(transaction_id, index as u16)?)
+        })
+        .collect()
+}
+
+fn same_data_output(created: &Output, output: &Output) -> bool {
+    match (created, output) {
+        (Output::Basic(created), Output::Basic(output)) => {
+            created.features().metadata().is_some()
+                && created.features() == output.features()
+                && created.unlock_conditions() == output.unlock_conditions()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iota_sdk::types::block::{
+        address::{Address, Ed25519Address, ToBech32Ext},
+        output::unlock_condition::AddressUnlockCondition,
+    };
+
+    use super::*;
+    use crate::ledger::{Ledger, SimulatedLedger};
+
+    #[tokio::test]
+    async fn data_outputs_are_found_after_a_remainder() {
+        let ledger = SimulatedLedger::new();
+        let (rent_structure, token_supply) = (ledger.rent_structure().await.unwrap(), ledger.token_supply().await.unwrap());
+        let address = Address::Ed25519(Ed25519Address::new([1; 32])).to_bech32_unchecked("smr");
+        let output = |data: &[u8]| {
+            data_output(&address, "t", data.to_vec(), &WriteOptions::default(), rent_structure, token_supply).unwrap()
+        };
+        let remainder = BasicOutputBuilder::new_with_amount(1_000_000)
+            .add_unlock_condition(AddressUnlockCondition::new(address))
+            .finish_output(token_supply)
+            .unwrap();
+
+        let transaction_id = TransactionId::new([2; 32]);
+        let created = vec![remainder, output(b"a"), output(b"b"), output(b"a")];
+        let ids = output_ids_of(transaction_id, &created, &[output(b"a"), output(b"a"), output(b"b")]).unwrap();
+        let indexes: Vec<u16> = ids.iter().map(OutputId::index).collect();
+        // Equal outputs get distinct indexes
+        assert_eq!(indexes, [1, 3, 2]);
+
+        assert_eq!(output_id_of(transaction_id, &created, &output(b"b")).unwrap().index(), 2);
+        assert!(matches!(output_id_of(transaction_id, &created, &output(b"c")), Err(PurityError::NotFound(_))));
+    }
+}